@@ -0,0 +1,141 @@
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Default size, in bytes, of the buffer the panic report is formatted into, without the sections
+/// added by the features below
+const DEFAULT_BUFFER_SIZE: usize = 1024;
+
+/// Room, in bytes, the default buffer size makes for the report section of each of these features
+const SECTION_SIZES: &[(&str, usize)] = &[
+    ("BUILD_INFO", 256),
+    ("CORE_REGISTERS", 384),
+    ("EXCEPTION_FRAME", 384),
+    ("FAULT_REGISTERS", 384),
+];
+
+/// Backtrace features; the default buffer size makes room for a full backtrace for each of them
+const BACKTRACE_FEATURES: &[&str] = &["BACKTRACE_EXIDX", "BACKTRACE_FP", "BACKTRACE_SCAN"];
+
+/// Room, in bytes, for the header and the end marker of a backtrace
+const BACKTRACE_OVERHEAD: usize = 64;
+
+/// Room, in bytes, for a backtrace frame, `  #NN 0x00000000`
+const BACKTRACE_FRAME_SIZE: usize = 18;
+
+/// Default exit code reported by the panic handler with the `exit-extended` feature
+const DEFAULT_EXIT_CODE: u32 = 1;
+
+/// Default maximum number of frames in a backtrace
+const DEFAULT_BACKTRACE_DEPTH: usize = 32;
+
+/// Smallest buffer that can hold the truncation marker plus a useful amount of the message
+const MIN_BUFFER_SIZE: usize = 64;
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let target = env::var("TARGET").unwrap();
+
+    println!("cargo:rustc-check-cfg=cfg(armv6m)");
+    println!("cargo:rustc-check-cfg=cfg(armv7m)");
+    println!("cargo:rustc-check-cfg=cfg(armv8m)");
+    println!("cargo:rustc-check-cfg=cfg(armv8m_base)");
+    println!("cargo:rustc-check-cfg=cfg(armv8m_main)");
+    println!("cargo:rustc-check-cfg=cfg(has_fpu)");
+
+    if target.starts_with("thumbv6m-") {
+        println!("cargo:rustc-cfg=armv6m");
+    } else if target.starts_with("thumbv7m-") || target.starts_with("thumbv7em-") {
+        println!("cargo:rustc-cfg=armv7m");
+    } else if target.starts_with("thumbv8m.base") {
+        println!("cargo:rustc-cfg=armv8m");
+        println!("cargo:rustc-cfg=armv8m_base");
+    } else if target.starts_with("thumbv8m.main") {
+        println!("cargo:rustc-cfg=armv8m");
+        println!("cargo:rustc-cfg=armv8m_main");
+    }
+
+    if target.ends_with("-eabihf") {
+        println!("cargo:rustc-cfg=has_fpu");
+    }
+
+    let backtrace_depth: usize = var("PANIC_SEMIHOSTING_BACKTRACE_DEPTH", DEFAULT_BACKTRACE_DEPTH);
+
+    let buffer_size: usize = var(
+        "PANIC_SEMIHOSTING_BUFFER_SIZE",
+        default_buffer_size(backtrace_depth),
+    );
+    if buffer_size < MIN_BUFFER_SIZE {
+        panic!(
+            "PANIC_SEMIHOSTING_BUFFER_SIZE must be at least {} bytes (got {})",
+            MIN_BUFFER_SIZE, buffer_size
+        );
+    }
+
+    let exit_code: u32 = var("PANIC_SEMIHOSTING_EXIT_CODE", DEFAULT_EXIT_CODE);
+
+    let mut config = File::create(out.join("config.rs")).unwrap();
+    writeln!(config, "pub const BUFFER_SIZE: usize = {};", buffer_size).unwrap();
+    writeln!(config, "pub const EXIT_CODE: u32 = {};", exit_code).unwrap();
+    writeln!(
+        config,
+        "pub const BACKTRACE_DEPTH: usize = {};",
+        backtrace_depth
+    )
+    .unwrap();
+
+    // put the linker script fragments somewhere the linker can find them
+    File::create(out.join("panic-semihosting.x"))
+        .unwrap()
+        .write_all(include_bytes!("panic-semihosting.x"))
+        .unwrap();
+    File::create(out.join("panic-semihosting-exidx.x"))
+        .unwrap()
+        .write_all(include_bytes!("panic-semihosting-exidx.x"))
+        .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=panic-semihosting.x");
+    println!("cargo:rerun-if-changed=panic-semihosting-exidx.x");
+}
+
+/// Returns a buffer size that fits the sections of the report added by the enabled features
+fn default_buffer_size(backtrace_depth: usize) -> usize {
+    let mut size = DEFAULT_BUFFER_SIZE;
+
+    for &(feature, section_size) in SECTION_SIZES {
+        if feature_enabled(feature) {
+            size += section_size;
+        }
+    }
+
+    for feature in BACKTRACE_FEATURES {
+        if feature_enabled(feature) {
+            size += BACKTRACE_OVERHEAD + backtrace_depth * BACKTRACE_FRAME_SIZE;
+        }
+    }
+
+    size
+}
+
+fn feature_enabled(feature: &str) -> bool {
+    env::var_os(format!("CARGO_FEATURE_{}", feature)).is_some()
+}
+
+/// Reads an optional integer from the environment of the build
+fn var<T>(name: &str, default: T) -> T
+where
+    T: FromStr,
+{
+    println!("cargo:rerun-if-env-changed={}", name);
+
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not a valid integer (got {:?})", name, value)),
+        Err(_) => default,
+    }
+}
@@ -3,8 +3,46 @@ set -euxo pipefail
 main() {
     cargo check --target $TARGET
 
+    # NOTE the symbolizer and the panic record require a newer compiler than the MSRV
+    if [ $TARGET = x86_64-unknown-linux-gnu ] && [ $TRAVIS_RUST_VERSION != 1.32.0 ]; then
+        cargo test --manifest-path symbolizer/Cargo.toml
+        cargo test --features panic-record
+        cargo test --features flash-record
+        cargo test --features backtrace-exidx,backtrace-fp,backtrace-scan
+    fi
+
+    # NOTE the target specific code of the optional features requires a newer compiler than the
+    # MSRV
+    if [ $TARGET != x86_64-unknown-linux-gnu ] && [ $TRAVIS_RUST_VERSION != 1.32.0 ]; then
+        local features=backtrace-exidx,backtrace-fp,backtrace-scan,build-info,cmdline
+        features=$features,core-registers,default-handler,exception-frame,exit-extended
+        features=$features,fault-registers,flash-record,hard-fault,hooks,reset-without-debugger
+        features=$features,timestamp-sys-clock,timestamp-sys-time,timestamp-user
+        # ARMv6-M and ARMv8-M Baseline have no ITM and no cycle counter
+        local baseline=false
+        if [ $TARGET = thumbv6m-none-eabi ] || [ $TARGET = thumbv8m.base-none-eabi ]; then
+            baseline=true
+        fi
+        if [ $baseline = false ]; then
+            features=$features,itm,timestamp-dwt
+        fi
+
+        cargo check --target $TARGET --features $features
+        cargo check --target $TARGET --no-default-features --features $features
+
+        if [ $baseline = true ]; then
+            for feature in itm timestamp-dwt; do
+                if cargo check --target $TARGET --features $feature; then
+                    echo "the \`$feature\` feature must not build on $TARGET"
+                    exit 1
+                fi
+            done
+        fi
+    fi
+
     if [ $TRAVIS_RUST_VERSION = nightly ]; then
         cargo check --target $TARGET --features inline-asm
+        cargo check --target $TARGET --features alloc
     fi
 }
 
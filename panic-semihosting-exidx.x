@@ -0,0 +1,22 @@
+/* Linker script fragment for the `backtrace-exidx` feature of panic-semihosting */
+/* Add it to the link by passing `-Tpanic-semihosting-exidx.x` to the linker, after `-Tlink.x` */
+
+SECTIONS
+{
+  /* Unwind tables; `link.x` discards them */
+  .ARM.extab : ALIGN(4)
+  {
+    __extab_start = .;
+    *(.ARM.extab .ARM.extab.*);
+    . = ALIGN(4);
+    __extab_end = .;
+  } > FLASH
+
+  .ARM.exidx : ALIGN(4)
+  {
+    __exidx_start = .;
+    *(.ARM.exidx .ARM.exidx.*);
+    __exidx_end = .;
+  } > FLASH
+}
+INSERT AFTER .rodata;
@@ -0,0 +1,30 @@
+/* Linker script fragment for panic-semihosting */
+/* Add it to the link by passing `-Tpanic-semihosting.x` to the linker, after `-Tlink.x` */
+
+SECTIONS
+{
+  /* Safe-state hooks registered with `panic_hook!` */
+  .panic_semihosting_hooks : ALIGN(4)
+  {
+    __panic_semihosting_hooks_start = .;
+    KEEP(*(.panic_semihosting_hooks .panic_semihosting_hooks.*));
+    __panic_semihosting_hooks_end = .;
+  } > FLASH
+
+  /* Build metadata placed by `build_info!` */
+  .panic_semihosting_build_info :
+  {
+    __panic_semihosting_build_info_start = .;
+    KEEP(*(.panic_semihosting_build_info .panic_semihosting_build_info.*));
+    __panic_semihosting_build_info_end = .;
+  } > FLASH
+
+  /* GNU build-id note, added by the linker when it's passed `--build-id` */
+  .note.gnu.build-id : ALIGN(4)
+  {
+    __panic_semihosting_build_id_start = .;
+    KEEP(*(.note.gnu.build-id));
+    __panic_semihosting_build_id_end = .;
+  } > FLASH
+}
+INSERT AFTER .rodata;
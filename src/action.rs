@@ -0,0 +1,143 @@
+//! Runtime selection of the terminal action
+
+#[cfg(feature = "cmdline")]
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "cmdline")]
+use sh::nr;
+
+/// Action performed by the panic handler after reporting the panic
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TerminalAction {
+    /// Trigger a breakpoint
+    Breakpoint,
+    /// Perform an exit semihosting call
+    Exit,
+}
+
+/// Select the default terminal action, i.e. the one chosen at compile time
+#[cfg(feature = "cmdline")]
+const DEFAULT: usize = 0;
+/// Select `TerminalAction::Breakpoint`
+const BREAKPOINT: usize = 1;
+/// Select `TerminalAction::Exit`
+const EXIT: usize = 2;
+
+/// Marks a valid `PANIC_SEMIHOSTING_ACTION`; the action is stored in the least significant byte
+const MAGIC: usize = 0x5053_4100;
+
+/// Terminal action selected at runtime, `MAGIC` plus `BREAKPOINT` or `EXIT`
+///
+/// The symbol is not mangled so that debugger scripts and loaders can poke it. It lives in the
+/// `.uninit` section, which the runtime doesn't initialize on boot, so a value written before the
+/// program starts is kept; any value without `MAGIC`, like the contents of RAM after a power-on
+/// reset, selects the default action.
+#[link_section = ".uninit.PANIC_SEMIHOSTING_ACTION"]
+#[no_mangle]
+static PANIC_SEMIHOSTING_ACTION: AtomicUsize = AtomicUsize::new(0);
+
+/// Size of the buffer the command line is read into; the host refuses to write a longer command line
+#[cfg(feature = "cmdline")]
+const CMDLINE_SIZE: usize = 128;
+
+/// `CMDLINE_ACTION` value until the command line has been read
+#[cfg(feature = "cmdline")]
+const UNREAD: usize = 3;
+
+/// Terminal action found in the command line, `DEFAULT` if none, or `UNREAD`
+#[cfg(feature = "cmdline")]
+static CMDLINE_ACTION: AtomicUsize = AtomicUsize::new(UNREAD);
+
+pub fn set(action: TerminalAction) {
+    let word = match action {
+        TerminalAction::Breakpoint => BREAKPOINT,
+        TerminalAction::Exit => EXIT,
+    };
+
+    PANIC_SEMIHOSTING_ACTION.store(MAGIC | word, Ordering::Relaxed);
+}
+
+pub fn get() -> TerminalAction {
+    let word = PANIC_SEMIHOSTING_ACTION.load(Ordering::Relaxed);
+    if word & !0xff == MAGIC {
+        match word & 0xff {
+            BREAKPOINT => return TerminalAction::Breakpoint,
+            EXIT => return TerminalAction::Exit,
+            _ => {}
+        }
+    }
+
+    #[cfg(feature = "cmdline")]
+    {
+        if let Some(action) = from_cmdline() {
+            return action;
+        }
+    }
+
+    match () {
+        #[cfg(feature = "exit")]
+        () => TerminalAction::Exit,
+        #[cfg(not(feature = "exit"))]
+        () => TerminalAction::Breakpoint,
+    }
+}
+
+/// Looks for a `--panic-action=exit` or `--panic-action=breakpoint` argument in the command line
+/// passed to the program by the host
+///
+/// The command line is read once, the first time this is called with a debugger attached and the
+/// semihosting sink enabled; until then this returns `None`.
+#[cfg(feature = "cmdline")]
+fn from_cmdline() -> Option<TerminalAction> {
+    let word = match CMDLINE_ACTION.load(Ordering::Relaxed) {
+        UNREAD => {
+            // a semihosting call without a debugger would fault or lock up the core
+            if !::debugger_attached() || !::sink::semihosting_enabled() {
+                return None;
+            }
+
+            let word = match read_cmdline() {
+                Some(TerminalAction::Breakpoint) => BREAKPOINT,
+                Some(TerminalAction::Exit) => EXIT,
+                None => DEFAULT,
+            };
+            CMDLINE_ACTION.store(word, Ordering::Relaxed);
+            word
+        }
+        word => word,
+    };
+
+    match word {
+        BREAKPOINT => Some(TerminalAction::Breakpoint),
+        EXIT => Some(TerminalAction::Exit),
+        _ => None,
+    }
+}
+
+/// Reads the command line with `SYS_GET_CMDLINE` and parses it
+#[cfg(feature = "cmdline")]
+fn read_cmdline() -> Option<TerminalAction> {
+    let mut buffer = [0u8; CMDLINE_SIZE];
+    // the host overwrites the second word with the length of the command line
+    let mut block = [buffer.as_mut_ptr() as usize, buffer.len()];
+
+    let ret = unsafe { sh::syscall1(nr::GET_CMDLINE, &mut block as *mut [usize; 2] as usize) };
+    if ret != 0 {
+        return None;
+    }
+
+    let len = unsafe { ptr::read_volatile(&block[1]) };
+    let cmdline = &buffer[..len.min(buffer.len())];
+
+    let mut action = None;
+    for arg in cmdline.split(|b| *b == b' ') {
+        match arg {
+            b"--panic-action=breakpoint" => action = Some(TerminalAction::Breakpoint),
+            b"--panic-action=exit" => action = Some(TerminalAction::Exit),
+            _ => {}
+        }
+    }
+
+    action
+}
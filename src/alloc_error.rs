@@ -0,0 +1,79 @@
+//! Allocation error reporting
+
+#[cfg(not(test))]
+use core::alloc::Layout;
+use core::cell::Cell;
+#[cfg(not(test))]
+use core::fmt::Write;
+
+use cortex_m::interrupt::{self, Mutex};
+
+#[cfg(not(test))]
+use buffer::Buffer;
+
+/// Usage statistics of the heap, as reported by the allocator
+#[derive(Clone, Copy, Debug)]
+pub struct AllocatorStats {
+    /// Number of bytes in use
+    pub used: usize,
+    /// Number of bytes available for allocation
+    pub free: usize,
+}
+
+/// Function that returns the allocator statistics
+type StatsHook = fn() -> AllocatorStats;
+
+/// The function registered with `set_stats_hook`
+static STATS_HOOK: Mutex<Cell<Option<StatsHook>>> = Mutex::new(Cell::new(None));
+
+pub fn set_stats_hook(hook: StatsHook) {
+    interrupt::free(|cs| STATS_HOOK.borrow(cs).set(Some(hook)));
+}
+
+/// Calls the registered statistics hook, if any
+#[cfg_attr(test, allow(dead_code))]
+fn stats() -> Option<AllocatorStats> {
+    interrupt::free(|cs| STATS_HOOK.borrow(cs).get()).map(|hook| hook())
+}
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    interrupt::disable();
+
+    #[cfg(feature = "hooks")]
+    ::run_hooks();
+
+    // if the allocation failed while a report was being written to the sinks, finish that first
+    ::sink::resume();
+
+    // NOTE(unsafe) interrupts are disabled; if the allocation failed while a panic was being
+    // formatted that report is abandoned
+    let mut buffer = unsafe { Buffer::take() };
+    ::timestamp::write(&mut buffer).ok();
+    writeln!(
+        buffer,
+        "memory allocation of {} bytes (alignment {}) failed",
+        layout.size(),
+        layout.align()
+    )
+    .ok();
+
+    if let Some(stats) = stats() {
+        writeln!(
+            buffer,
+            "heap: {} bytes used, {} bytes free",
+            stats.used, stats.free
+        )
+        .ok();
+    }
+
+    ::sink::write(buffer.as_bytes());
+
+    match () {
+        #[cfg(feature = "exit-extended")]
+        () => ::terminate_with(::ExitReason::AllocError),
+        #[cfg(not(feature = "exit-extended"))]
+        () => ::terminate(),
+    }
+}
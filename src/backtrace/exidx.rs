@@ -0,0 +1,737 @@
+//! Precise unwinding using the ARM exception handling ABI (EHABI) tables
+//!
+//! `.ARM.exidx` is a table, sorted by address, with one entry per function. Each entry is a pair of
+//! words: the (PREL31 encoded) address of the function and either the function's unwind
+//! instructions, a PREL31 offset to them in `.ARM.extab`, or `EXIDX_CANTUNWIND`. The unwind
+//! instructions undo the function's prologue: they adjust the virtual stack pointer (`vsp`) and
+//! pop the saved registers, LR among them, from the stack.
+//!
+//! Functions marked `EXIDX_CANTUNWIND`, like the ones of the precompiled `core`, are unwound
+//! through the frame record that `r7` points to instead; Thumb code keeps frame records in those
+//! functions. Unwinding ends at `main`, whose caller, cortex-m-rt's `Reset`, has no unwind table
+//! entry.
+//!
+//! See "Exception Handling ABI for the ARM Architecture" (ARM IHI 0038) for the details.
+
+use core::fmt;
+
+#[cfg(test)]
+use self::tests::{exidx, extab};
+use super::{in_text, main_address, read_word, stack_top, Frames, Registers, EXC_RETURN};
+
+#[cfg(not(test))]
+extern "C" {
+    // Provided by `panic-semihosting.x`
+    static __exidx_start: u32;
+    static __exidx_end: u32;
+    static __extab_start: u32;
+    static __extab_end: u32;
+}
+
+/// Entry data of functions that can't be unwound
+const EXIDX_CANTUNWIND: u32 = 1;
+
+/// Maximum number of instruction bytes of a single function: 3 in the first word plus up to 255
+/// additional words of 4 bytes each
+const MAX_INSTRUCTIONS: usize = 3 + 255 * 4;
+
+/// Why unwinding stopped
+enum Stop {
+    /// `main`, the outermost frame, has been unwound
+    End,
+    /// The return address is an `EXC_RETURN` value: the function is an exception handler
+    ExceptionEntry,
+    /// The return address doesn't point into `.text`
+    BadReturnAddress,
+    /// The function has no unwind table entry
+    NoEntry,
+    /// The function is marked as not unwindable and has no usable frame record
+    CantUnwind,
+    /// The unwind instructions are malformed or not supported
+    BadInstructions,
+    /// The unwind instructions tried to read memory outside of the stack
+    OutOfBounds,
+}
+
+impl Stop {
+    fn note(&self) -> Option<&'static str> {
+        match *self {
+            Stop::End => None,
+            Stop::ExceptionEntry => Some("exception entry"),
+            Stop::BadReturnAddress => Some("return address outside of `.text`"),
+            Stop::NoEntry => Some("no unwind table entry; was `-C force-unwind-tables` used?"),
+            Stop::CantUnwind => Some("function can't be unwound"),
+            Stop::BadInstructions => Some("unsupported unwind instructions"),
+            Stop::OutOfBounds => Some("unwinding left the stack"),
+        }
+    }
+}
+
+/// Writes the backtrace of the current stack; never inlined so that the captured registers
+/// describe this function's frame
+#[inline(never)]
+pub fn write(frames: &mut Frames) -> fmt::Result {
+    let registers = Registers::capture();
+
+    let stack = Stack {
+        low: registers.sp,
+        high: stack_top(),
+    };
+
+    if stack.low >= stack.high {
+        return frames.note("the stack pointer is outside of the main stack");
+    }
+
+    let mut state = State {
+        r: [0; 16],
+        stack,
+        unwound: false,
+        outermost: false,
+    };
+    state.r[7] = registers.r7;
+    state.r[13] = registers.sp;
+    state.r[14] = registers.lr;
+    state.r[15] = registers.pc;
+
+    unwind(frames, &mut state)
+}
+
+/// Writes the return addresses of the frames unwound from `state`
+fn unwind(frames: &mut Frames, state: &mut State) -> fmt::Result {
+    loop {
+        match state.step() {
+            Ok(return_address) => {
+                if !frames.push(return_address)? {
+                    return Ok(());
+                }
+            }
+            Err(stop) => {
+                return match stop.note() {
+                    Some(note) => frames.note(note),
+                    None => Ok(()),
+                };
+            }
+        }
+    }
+}
+
+/// Bounds of the memory that unwinding is allowed to read
+#[derive(Clone, Copy)]
+struct Stack {
+    low: u32,
+    high: u32,
+}
+
+impl Stack {
+    fn read(&self, address: u32) -> Result<u32, Stop> {
+        if address & 0b11 != 0 || address < self.low || address >= self.high {
+            return Err(Stop::OutOfBounds);
+        }
+
+        // NOTE(unsafe) word aligned address within the stack
+        Ok(unsafe { read_word(address) })
+    }
+}
+
+/// Virtual register set
+struct State {
+    r: [u32; 16],
+    stack: Stack,
+    /// Set once `r[15]` holds a return address rather than the address of the current instruction
+    unwound: bool,
+    /// Set once `main` has been unwound
+    outermost: bool,
+}
+
+impl State {
+    /// Unwinds one frame and returns the return address of the function at `r[15]`
+    fn step(&mut self) -> Result<u32, Stop> {
+        // `Reset` called `main`; there's nothing above it
+        if self.outermost {
+            return Err(Stop::End);
+        }
+
+        // a return address may point past the end of the calling function, e.g. when it calls a
+        // function that never returns, like `panic`; the call instruction precedes it
+        let pc = if self.unwound {
+            (self.r[15] & !1) - 1
+        } else {
+            self.r[15] & !1
+        };
+
+        let entry = find_entry(pc)?;
+        match Instructions::from_entry(entry) {
+            Ok(instructions) => self.execute(instructions)?,
+            Err(Stop::CantUnwind) => self.pop_frame_record()?,
+            Err(stop) => return Err(stop),
+        }
+
+        let return_address = self.r[15];
+        if return_address >= EXC_RETURN {
+            return Err(Stop::ExceptionEntry);
+        }
+        // the tables describe every function that has an entry, so this is a corrupted stack
+        if !in_text(return_address & !1) {
+            return Err(Stop::BadReturnAddress);
+        }
+
+        // from the return address onwards `vsp` must only move towards the top of the stack
+        self.stack.low = self.r[13];
+        self.unwound = true;
+        self.outermost = prel31(entry, read_exidx(entry)?) == main_address();
+
+        Ok(return_address)
+    }
+
+    /// Undoes the prologue of the current function by interpreting its unwind `instructions`
+    fn execute(&mut self, mut instructions: Instructions) -> Result<(), Stop> {
+        let mut vsp = self.r[13];
+        let mut popped_pc = false;
+
+        while let Some(op) = instructions.next()? {
+            match op {
+                // 00xxxxxx: vsp = vsp + (xxxxxx << 2) + 4
+                0x00..=0x3f => vsp = vsp.wrapping_add((u32::from(op & 0x3f) << 2) + 4),
+                // 01xxxxxx: vsp = vsp - (xxxxxx << 2) - 4
+                0x40..=0x7f => vsp = vsp.wrapping_sub((u32::from(op & 0x3f) << 2) + 4),
+                // 1000iiii iiiiiiii: pop {r4-r15} under mask
+                0x80..=0x8f => {
+                    let op2 = instructions.operand()?;
+                    let mask = (u16::from(op & 0x0f) << 8) | u16::from(op2);
+                    if mask == 0 {
+                        // refuse to unwind
+                        return Err(Stop::CantUnwind);
+                    }
+
+                    popped_pc |= mask & (1 << 11) != 0;
+                    vsp = self.pop(vsp, u32::from(mask) << 4)?;
+                }
+                // 1001nnnn: vsp = r[nnnn]; r13 and r15 are reserved
+                0x9d | 0x9f => return Err(Stop::BadInstructions),
+                0x90..=0x9f => vsp = self.r[usize::from(op & 0x0f)],
+                // 10100nnn: pop {r4-r[4+nnn]}
+                0xa0..=0xa7 => vsp = self.pop(vsp, range_mask(4, 4 + u32::from(op & 0x07)))?,
+                // 10101nnn: pop {r4-r[4+nnn], r14}
+                0xa8..=0xaf => {
+                    vsp = self.pop(vsp, range_mask(4, 4 + u32::from(op & 0x07)) | (1 << 14))?
+                }
+                // 10110000: finish
+                0xb0 => break,
+                // 10110001 0000iiii: pop {r0-r3} under mask
+                0xb1 => {
+                    let mask = instructions.operand()?;
+                    if mask == 0 || mask & 0xf0 != 0 {
+                        return Err(Stop::BadInstructions);
+                    }
+
+                    vsp = self.pop(vsp, u32::from(mask))?;
+                }
+                // 10110010 uleb128: vsp = vsp + 0x204 + (uleb128 << 2)
+                0xb2 => {
+                    let mut value = 0u32;
+                    let mut shift = 0;
+                    loop {
+                        let byte = instructions.operand()?;
+                        if shift > 28 {
+                            return Err(Stop::BadInstructions);
+                        }
+
+                        value |= u32::from(byte & 0x7f) << shift;
+                        shift += 7;
+                        if byte & 0x80 == 0 {
+                            break;
+                        }
+                    }
+
+                    vsp = vsp.wrapping_add(0x204).wrapping_add(value << 2);
+                }
+                // 10110011 sssscccc: pop VFP registers saved by FSTMFDX
+                0xb3 => {
+                    let op2 = instructions.operand()?;
+                    vsp = vsp.wrapping_add(u32::from(op2 & 0x0f) * 8 + 8 + 4);
+                }
+                // 10111nnn: pop VFP {d8-d[8+nnn]} saved by FSTMFDX
+                0xb8..=0xbf => vsp = vsp.wrapping_add(u32::from(op & 0x07) * 8 + 8 + 4),
+                // 11001000 sssscccc, 11001001 sssscccc: pop VFP registers saved by VPUSH
+                0xc8 | 0xc9 => {
+                    let op2 = instructions.operand()?;
+                    vsp = vsp.wrapping_add(u32::from(op2 & 0x0f) * 8 + 8);
+                }
+                // 11010nnn: pop VFP {d8-d[8+nnn]} saved by VPUSH
+                0xd0..=0xd7 => vsp = vsp.wrapping_add(u32::from(op & 0x07) * 8 + 8),
+                // spare, and Intel Wireless MMX instructions, which Cortex-M doesn't have
+                _ => return Err(Stop::BadInstructions),
+            }
+        }
+
+        if !popped_pc {
+            self.r[15] = self.r[14];
+        }
+        self.r[13] = vsp;
+
+        Ok(())
+    }
+
+    /// Unwinds a function that has no unwind instructions through the frame record, the saved `r7`
+    /// followed by the saved `LR`, that `r7` points to
+    ///
+    /// If the function didn't push a frame record `r7` still points to the one of its caller, so
+    /// the caller is missing from the backtrace but the frames above it are right.
+    fn pop_frame_record(&mut self) -> Result<(), Stop> {
+        let fp = self.r[7];
+        let (next, lr) = match (self.stack.read(fp), self.stack.read(fp.wrapping_add(4))) {
+            (Ok(next), Ok(lr)) => (next, lr),
+            _ => return Err(Stop::CantUnwind),
+        };
+
+        if !in_text(lr & !1) {
+            return Err(Stop::CantUnwind);
+        }
+
+        self.r[7] = next;
+        self.r[13] = fp + 8;
+        self.r[14] = lr;
+        self.r[15] = lr;
+
+        Ok(())
+    }
+
+    /// Pops the registers in `mask`, lowest numbered first, starting at `vsp`
+    fn pop(&mut self, mut vsp: u32, mask: u32) -> Result<u32, Stop> {
+        let mut new_vsp = None;
+
+        for i in 0..16 {
+            if mask & (1 << i) != 0 {
+                let value = self.stack.read(vsp)?;
+                vsp += 4;
+
+                if i == 13 {
+                    new_vsp = Some(value);
+                } else {
+                    self.r[i] = value;
+                }
+            }
+        }
+
+        // popping r13 sets vsp to the popped value
+        Ok(new_vsp.unwrap_or(vsp))
+    }
+}
+
+/// Returns a mask with bits `first` through `last`, both included, set
+fn range_mask(first: u32, last: u32) -> u32 {
+    ((1 << (last + 1)) - 1) & !((1 << first) - 1)
+}
+
+/// Decodes a PREL31 offset stored at `address`
+fn prel31(address: u32, word: u32) -> u32 {
+    // sign extend from 31 bits
+    let offset = ((word << 1) as i32 >> 1) as u32;
+    address.wrapping_add(offset)
+}
+
+/// Returns the bounds of `.ARM.exidx`
+#[cfg(not(test))]
+fn exidx() -> (u32, u32) {
+    unsafe {
+        (
+            &__exidx_start as *const u32 as u32,
+            &__exidx_end as *const u32 as u32,
+        )
+    }
+}
+
+/// Returns the bounds of `.ARM.extab`
+#[cfg(not(test))]
+fn extab() -> (u32, u32) {
+    unsafe {
+        (
+            &__extab_start as *const u32 as u32,
+            &__extab_end as *const u32 as u32,
+        )
+    }
+}
+
+/// Reads a word from `.ARM.exidx`
+fn read_exidx(address: u32) -> Result<u32, Stop> {
+    read_section(address, exidx())
+}
+
+/// Reads a word from `.ARM.extab`
+fn read_extab(address: u32) -> Result<u32, Stop> {
+    read_section(address, extab())
+}
+
+fn read_section(address: u32, (start, end): (u32, u32)) -> Result<u32, Stop> {
+    if address & 0b11 != 0 || address < start || address >= end {
+        return Err(Stop::BadInstructions);
+    }
+
+    // NOTE(unsafe) word aligned address within the section
+    Ok(unsafe { read_word(address) })
+}
+
+/// Unwind instructions of a function, read a byte at a time
+struct Instructions {
+    /// The current word; instructions are packed most significant byte first
+    word: u32,
+    /// Bytes left in `word`
+    bytes: u32,
+    /// Address of the next word in `.ARM.extab`
+    next: u32,
+    /// Words left after `word`
+    words: u32,
+    /// Total number of bytes read so far
+    read: usize,
+}
+
+impl Instructions {
+    /// Sets up the unwind instructions of the `.ARM.exidx` `entry`
+    fn from_entry(entry: u32) -> Result<Self, Stop> {
+        let data = read_exidx(entry + 4)?;
+
+        if data == EXIDX_CANTUNWIND {
+            return Err(Stop::CantUnwind);
+        }
+
+        if data & (1 << 31) != 0 {
+            // compact model inlined in the table entry; only personality routine 0 fits
+            return Self::compact(data, 0);
+        }
+
+        let address = prel31(entry + 4, data);
+        let word = read_extab(address)?;
+        if word & (1 << 31) != 0 {
+            return Self::compact(word, address + 4);
+        }
+
+        // generic model: a PREL31 offset to the personality routine followed by the
+        // instructions in the same layout used by the compact models 1 and 2
+        let word = read_extab(address + 4)?;
+        Ok(Instructions {
+            word: word << 8,
+            bytes: 3,
+            next: address + 8,
+            words: word >> 24,
+            read: 0,
+        })
+    }
+
+    /// Sets up the instructions of a compact model entry whose first word is `word`; additional
+    /// words, if any, start at `next`
+    fn compact(word: u32, next: u32) -> Result<Self, Stop> {
+        match (word >> 24) & 0x0f {
+            // Su16: three instructions in the first word
+            0 => Ok(Instructions {
+                word: word << 8,
+                bytes: 3,
+                next,
+                words: 0,
+                read: 0,
+            }),
+            // Lu16 and Lu32: two instructions in the first word followed by additional words
+            1 | 2 => Ok(Instructions {
+                word: word << 16,
+                bytes: 2,
+                next,
+                words: (word >> 16) & 0xff,
+                read: 0,
+            }),
+            _ => Err(Stop::BadInstructions),
+        }
+    }
+
+    /// Returns the next instruction byte, or `None` at the end of the instructions
+    ///
+    /// Running out of instructions is an implicit `finish`: only the unused bytes of the last word
+    /// are padded with `finish`. A word that can't be read is an error.
+    fn next(&mut self) -> Result<Option<u8>, Stop> {
+        if self.read == MAX_INSTRUCTIONS {
+            return Err(Stop::BadInstructions);
+        }
+
+        if self.bytes == 0 {
+            if self.words == 0 {
+                return Ok(None);
+            }
+
+            self.word = read_extab(self.next)?;
+            self.bytes = 4;
+            self.next += 4;
+            self.words -= 1;
+        }
+
+        let byte = (self.word >> 24) as u8;
+        self.word <<= 8;
+        self.bytes -= 1;
+        self.read += 1;
+
+        Ok(Some(byte))
+    }
+
+    /// Returns the operand byte of the current instruction
+    fn operand(&mut self) -> Result<u8, Stop> {
+        self.next()?.ok_or(Stop::BadInstructions)
+    }
+}
+
+/// Returns the address of the `.ARM.exidx` entry of the function that contains `pc`
+fn find_entry(pc: u32) -> Result<u32, Stop> {
+    let (start, end) = exidx();
+    let count = (end.saturating_sub(start)) / 8;
+
+    // binary search for the last entry whose function starts at or before `pc`
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let entry = start + mid * 8;
+        let function = prel31(entry, read_exidx(entry)?);
+
+        if function <= pc {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    if low == 0 {
+        Err(Stop::NoEntry)
+    } else {
+        Ok(start + (low - 1) * 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::super::tests::{map, MAIN};
+    use super::super::{Frames, TEXT};
+    use super::{unwind, Stack, State, Stop, EXIDX_CANTUNWIND};
+    use config::BACKTRACE_DEPTH;
+
+    ::std::thread_local! {
+        /// Bounds of the simulated `.ARM.exidx` and `.ARM.extab`
+        static SECTIONS: Cell<((u32, u32), (u32, u32))> = const { Cell::new(((0, 0), (0, 0))) };
+    }
+
+    const EXIDX: u32 = 0x0001_0000;
+    const EXTAB: u32 = 0x0001_8000;
+    const STACK: (u32, u32) = (0x2000_0000, 0x2000_1000);
+
+    /// `Reset`, which calls `MAIN`; it has no table entry
+    const RESET: u32 = TEXT.0;
+    /// Pushes `{r4, r7, lr}`; described by instructions inlined in its table entry
+    const INLINE: u32 = TEXT.0 + 0x1000;
+    /// Marked `EXIDX_CANTUNWIND`
+    const CANTUNWIND: u32 = TEXT.0 + 0x2000;
+    /// Allocates 0x200 bytes of locals and pushes `{r7, lr}`; needs a uleb128 operand
+    const ULEB128: u32 = TEXT.0 + 0x3000;
+    /// Allocates 4 bytes of locals and pushes `{r7, lr}`; uses the generic model
+    const GENERIC: u32 = TEXT.0 + 0x4000;
+    /// Described by instructions in `.ARM.extab` that end in the middle of an instruction
+    const TRUNCATED: u32 = TEXT.0 + 0x5000;
+    /// Described by instructions in `.ARM.extab` that continue past the end of the section
+    const OVERRUN: u32 = TEXT.0 + 0x6000;
+
+    /// Data of a table entry
+    enum Entry {
+        /// The entry data itself
+        Inline(u32),
+        /// Words in `.ARM.extab`
+        Extab(&'static [u32]),
+    }
+
+    pub fn exidx() -> (u32, u32) {
+        SECTIONS.with(|s| s.get().0)
+    }
+
+    pub fn extab() -> (u32, u32) {
+        SECTIONS.with(|s| s.get().1)
+    }
+
+    /// Encodes `target` as a PREL31 offset stored at `address`
+    fn prel31(address: u32, target: u32) -> u32 {
+        target.wrapping_sub(address) & 0x7fff_ffff
+    }
+
+    /// Maps the unwind tables of the test functions and the `stack` words, starting at the bottom
+    /// of the stack
+    fn map_tables(stack: &[u32]) {
+        let entries = [
+            // compact model 0: pop {r4, r7, lr}; finish
+            (MAIN, Entry::Inline(0x8084_09b0)),
+            (INLINE, Entry::Inline(0x8084_09b0)),
+            (CANTUNWIND, Entry::Inline(EXIDX_CANTUNWIND)),
+            // compact model 1 with an additional word: vsp += 0x208; pop {r7, lr}; finish
+            (ULEB128, Entry::Extab(&[0x8101_b201, 0x8408_b0b0])),
+            // generic model: the personality routine, then vsp += 8; pop {r7, lr}
+            (GENERIC, Entry::Extab(&[0x0000_0100, 0x0001_8408])),
+            // compact model 1: vsp += 4; pop {r4-r15} under mask, without the mask
+            (TRUNCATED, Entry::Extab(&[0x8100_0084])),
+            // compact model 1: vsp += 4; vsp += 4; and an additional word, which isn't there
+            (OVERRUN, Entry::Extab(&[0x8101_0000])),
+        ];
+
+        let mut words = Vec::new();
+        let mut extab = EXTAB;
+        for (i, &(function, ref data)) in entries.iter().enumerate() {
+            let entry = EXIDX + i as u32 * 8;
+            words.push((entry, prel31(entry, function)));
+            match *data {
+                Entry::Inline(data) => words.push((entry + 4, data)),
+                Entry::Extab(data) => {
+                    words.push((entry + 4, prel31(entry + 4, extab)));
+                    for &word in data {
+                        words.push((extab, word));
+                        extab += 4;
+                    }
+                }
+            }
+        }
+
+        for (i, &word) in stack.iter().enumerate() {
+            words.push((STACK.0 + i as u32 * 4, word));
+        }
+
+        map(words);
+        SECTIONS.with(|s| s.set(((EXIDX, EXIDX + entries.len() as u32 * 8), (EXTAB, extab))));
+    }
+
+    fn state(pc: u32, r7: u32) -> State {
+        let mut state = State {
+            r: [0; 16],
+            stack: Stack {
+                low: STACK.0,
+                high: STACK.1,
+            },
+            unwound: false,
+            outermost: false,
+        };
+        state.r[7] = r7;
+        state.r[13] = STACK.0;
+        state.r[15] = pc | 1;
+        state
+    }
+
+    #[test]
+    fn unwinds_to_main() {
+        // INLINE's saved {r4, r7, lr}, CANTUNWIND's frame record, then MAIN's {r4, r7, lr}
+        map_tables(&[
+            4,
+            STACK.0 + 12,
+            CANTUNWIND + 0x11,
+            0,
+            MAIN + 0x21,
+            5,
+            0,
+            RESET + 0x41,
+        ]);
+        let mut state = state(INLINE + 0x20, 0);
+
+        assert_eq!(state.step().ok(), Some(CANTUNWIND + 0x11));
+        assert_eq!(state.r[4], 4);
+        assert_eq!(state.r[13], STACK.0 + 12);
+
+        assert_eq!(state.step().ok(), Some(MAIN + 0x21));
+        assert_eq!(state.r[13], STACK.0 + 20);
+
+        assert_eq!(state.step().ok(), Some(RESET + 0x41));
+        assert_eq!(state.r[4], 5);
+
+        assert!(matches!(state.step(), Err(Stop::End)));
+    }
+
+    #[test]
+    fn uleb128() {
+        let mut stack = [0; 0x84];
+        stack[0x82] = 7;
+        stack[0x83] = MAIN + 0x21;
+        map_tables(&stack);
+        let mut state = state(ULEB128 + 0x10, 0);
+
+        assert_eq!(state.step().ok(), Some(MAIN + 0x21));
+        assert_eq!(state.r[7], 7);
+        assert_eq!(state.r[13], STACK.0 + 0x210);
+    }
+
+    #[test]
+    fn generic_model() {
+        map_tables(&[0, 0, 7, MAIN + 0x21]);
+        let mut state = state(GENERIC + 0x10, 0);
+
+        assert_eq!(state.step().ok(), Some(MAIN + 0x21));
+        assert_eq!(state.r[7], 7);
+        assert_eq!(state.r[13], STACK.0 + 16);
+    }
+
+    #[test]
+    fn no_entry() {
+        map_tables(&[]);
+        let mut state = state(RESET + 0x10, 0);
+
+        assert!(matches!(state.step(), Err(Stop::NoEntry)));
+    }
+
+    #[test]
+    fn return_address_outside_text() {
+        map_tables(&[4, 0, 0x1234_5679]);
+        let mut state = state(INLINE + 0x20, 0);
+
+        assert!(matches!(state.step(), Err(Stop::BadReturnAddress)));
+    }
+
+    #[test]
+    fn exception_entry() {
+        map_tables(&[4, 0, 0xffff_fff9]);
+        let mut state = state(INLINE + 0x20, 0);
+
+        assert!(matches!(state.step(), Err(Stop::ExceptionEntry)));
+    }
+
+    #[test]
+    fn cantunwind_without_frame_record() {
+        // `r7` points below the stack
+        map_tables(&[0, 0]);
+        let mut state = state(CANTUNWIND + 0x10, STACK.0 - 8);
+
+        assert!(matches!(state.step(), Err(Stop::CantUnwind)));
+    }
+
+    #[test]
+    fn truncated_instructions() {
+        map_tables(&[0, 0]);
+        let mut state = state(TRUNCATED + 0x10, 0);
+
+        assert!(matches!(state.step(), Err(Stop::BadInstructions)));
+    }
+
+    #[test]
+    fn instructions_overrun_extab() {
+        map_tables(&[0, 0]);
+        let mut state = state(OVERRUN + 0x10, 0);
+
+        assert!(matches!(state.step(), Err(Stop::BadInstructions)));
+    }
+
+    #[test]
+    fn depth_limit() {
+        // INLINE calls itself recursively, more times than the depth limit
+        let mut stack = Vec::new();
+        for _ in 0..BACKTRACE_DEPTH + 1 {
+            stack.extend_from_slice(&[4, 0, INLINE + 0x21]);
+        }
+        map_tables(&stack);
+        let mut state = state(INLINE + 0x20, 0);
+
+        let mut report = String::new();
+        unwind(&mut Frames::new(&mut report, "exidx").unwrap(), &mut state).unwrap();
+
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), BACKTRACE_DEPTH + 2);
+        assert_eq!(lines[1], "  #0 0x08001021");
+        assert_eq!(lines[BACKTRACE_DEPTH + 1], "  ... (more frames omitted)");
+    }
+}
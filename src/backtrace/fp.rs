@@ -0,0 +1,175 @@
+//! Frame pointer based unwinding
+//!
+//! When the program is compiled with `-C force-frame-pointers=yes` every function pushes a frame
+//! record, the caller's frame pointer (`r7` on Thumb) followed by the return address (`LR`), and
+//! points `r7` at it. The records form a linked list that ends at the frame record of `main`:
+//! cortex-m-rt's `Reset` calls `main` without pushing a frame record of its own, so that record
+//! holds a return address right after the `BL` to `main` and whatever `r7` held at reset, which
+//! usually lies outside the stack.
+
+use core::fmt;
+
+use super::{in_text, read_word, returns_from_main, stack_top, Frames, Registers, EXC_RETURN};
+
+/// Writes the backtrace of the current stack; never inlined so that the captured frame pointer
+/// points at this function's frame record
+#[inline(never)]
+pub fn write(frames: &mut Frames) -> fmt::Result {
+    let registers = Registers::capture();
+
+    let low = registers.sp;
+    let high = stack_top();
+    if low >= high {
+        return frames.note("the stack pointer is outside of the main stack");
+    }
+
+    walk(frames, registers.r7, low, high)
+}
+
+/// Writes the return addresses of the frame records, starting at `fp`, in the stack that spans
+/// from `low` to `high`
+fn walk(frames: &mut Frames, mut fp: u32, low: u32, high: u32) -> fmt::Result {
+    loop {
+        // the frame record must be word aligned, fit in the stack and be above the previous one
+        if fp & 0b11 != 0 || fp < low || fp >= high - 4 {
+            return frames.note("frame pointer chain broken");
+        }
+
+        // NOTE(unsafe) word aligned addresses within the stack
+        let (next, return_address) = unsafe { (read_word(fp), read_word(fp + 4)) };
+
+        if return_address >= EXC_RETURN {
+            return frames.note("exception entry");
+        }
+
+        if !in_text(return_address & !1) {
+            return frames.note("frame pointer chain broken");
+        }
+
+        if !frames.push(return_address)? {
+            return Ok(());
+        }
+
+        if returns_from_main(return_address) {
+            // outermost frame, `main`'s: `next` is the value `r7` had when `Reset` called `main`
+            return Ok(());
+        }
+
+        if next <= fp {
+            return frames.note("frame pointer chain broken");
+        }
+        fp = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::super::tests::{bl, map, MAIN};
+    use super::super::{Frames, TEXT};
+    use super::walk;
+
+    const STACK: (u32, u32) = (0x2000_0000, 0x2000_0100);
+
+    /// `Reset`, which calls `MAIN` from `CALL_MAIN`
+    const RESET: u32 = TEXT.0;
+    const CALL_MAIN: u32 = RESET + 0x3c;
+    /// A function that `MAIN` calls
+    const FUNCTION: u32 = TEXT.0 + 0x1000;
+
+    /// Maps `Reset`'s call to `main` and the `stack` words, starting at the bottom of the stack,
+    /// then walks the frame records starting at the bottom of the stack
+    fn backtrace(stack: &[u32]) -> String {
+        let mut words = Vec::new();
+        words.push((CALL_MAIN, bl(CALL_MAIN, MAIN)));
+        // not calls to `main`: `movs r0, r0` twice
+        words.push((FUNCTION + 0x1c, 0));
+        words.push((MAIN + 0x1c, 0));
+        for (i, &word) in stack.iter().enumerate() {
+            words.push((STACK.0 + i as u32 * 4, word));
+        }
+        map(words);
+
+        let mut report = String::new();
+        walk(
+            &mut Frames::new(&mut report, "frame pointers").unwrap(),
+            STACK.0,
+            STACK.0,
+            STACK.1,
+        )
+        .unwrap();
+        report
+    }
+
+    #[test]
+    fn ends_at_main() {
+        let report = backtrace(&[
+            STACK.0 + 8,
+            FUNCTION + 0x21,
+            STACK.0 + 16,
+            MAIN + 0x21,
+            // `r7` at reset
+            0xffff_ffff,
+            CALL_MAIN + 5,
+        ]);
+
+        assert_eq!(
+            report,
+            "stack backtrace (frame pointers):\n  \
+             #0 0x08001021\n  \
+             #1 0x08000121\n  \
+             #2 0x08000041\n"
+        );
+    }
+
+    #[test]
+    fn return_address_outside_text() {
+        let report = backtrace(&[STACK.0 + 8, 0x1234_5679]);
+
+        assert_eq!(
+            report,
+            "stack backtrace (frame pointers):\n  \
+             (frame pointer chain broken)\n"
+        );
+    }
+
+    #[test]
+    fn next_outside_stack() {
+        // `FUNCTION` doesn't return after a call to `main`
+        let report = backtrace(&[0x3000_0000, FUNCTION + 0x21]);
+
+        assert_eq!(
+            report,
+            "stack backtrace (frame pointers):\n  \
+             #0 0x08001021\n  \
+             (frame pointer chain broken)\n"
+        );
+    }
+
+    #[test]
+    fn next_below_previous() {
+        let report = backtrace(&[STACK.0 + 8, FUNCTION + 0x21, STACK.0, FUNCTION + 0x21]);
+
+        assert_eq!(
+            report,
+            "stack backtrace (frame pointers):\n  \
+             #0 0x08001021\n  \
+             #1 0x08001021\n  \
+             (frame pointer chain broken)\n"
+        );
+    }
+
+    #[test]
+    fn exception_entry() {
+        let report = backtrace(&[STACK.0 + 8, FUNCTION + 0x21, 0, 0xffff_fff9]);
+
+        assert_eq!(
+            report,
+            "stack backtrace (frame pointers):\n  \
+             #0 0x08001021\n  \
+             (exception entry)\n"
+        );
+    }
+}
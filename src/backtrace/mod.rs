@@ -0,0 +1,299 @@
+//! Backtraces
+//!
+//! Every backtrace is printed as a list of return addresses, innermost first, one per line:
+//!
+//! ``` text
+//! stack backtrace (scan):
+//!   #0 0x08000b4b
+//!   #1 0x08000a0f
+//! ```
+//!
+//! The addresses can be symbolized on the host, e.g. with `arm-none-eabi-addr2line`.
+
+#[cfg(all(
+    target_arch = "arm",
+    any(feature = "backtrace-exidx", feature = "backtrace-fp")
+))]
+use core::arch::asm;
+use core::fmt;
+#[cfg(not(test))]
+use core::ptr;
+
+#[cfg(all(any(feature = "backtrace-exidx", feature = "backtrace-fp"), test))]
+use self::tests::main_address;
+#[cfg(all(any(feature = "backtrace-fp", feature = "backtrace-scan"), test))]
+use self::tests::read_halfword;
+#[cfg(test)]
+use self::tests::read_word;
+use config::BACKTRACE_DEPTH;
+use stack_top;
+
+#[cfg(feature = "backtrace-exidx")]
+mod exidx;
+#[cfg(feature = "backtrace-fp")]
+mod fp;
+#[cfg(feature = "backtrace-scan")]
+mod scan;
+
+extern "C" {
+    // Provided by cortex-m-rt's `link.x`
+    static __stext: u32;
+    static __etext: u32;
+}
+
+#[cfg(all(any(feature = "backtrace-exidx", feature = "backtrace-fp"), not(test)))]
+extern "C" {
+    // Provided by cortex-m-rt's `#[entry]`; `Reset` calls it
+    fn main() -> !;
+}
+
+/// Writes a backtrace of the current stack
+#[inline(never)]
+pub fn write(f: &mut dyn fmt::Write) -> fmt::Result {
+    #[cfg(feature = "backtrace-exidx")]
+    exidx::write(&mut Frames::new(f, "exidx")?)?;
+
+    #[cfg(feature = "backtrace-fp")]
+    fp::write(&mut Frames::new(f, "frame pointers")?)?;
+
+    #[cfg(feature = "backtrace-scan")]
+    scan::write(&mut Frames::new(f, "scan")?)?;
+
+    Ok(())
+}
+
+/// Writes the frames of a backtrace, up to `BACKTRACE_DEPTH` of them
+struct Frames<'a> {
+    f: &'a mut dyn fmt::Write,
+    count: usize,
+}
+
+impl<'a> Frames<'a> {
+    /// Writes the backtrace header
+    fn new(f: &'a mut dyn fmt::Write, method: &str) -> Result<Self, fmt::Error> {
+        writeln!(f, "stack backtrace ({}):", method)?;
+        Ok(Frames { f, count: 0 })
+    }
+
+    /// Writes a frame; returns `false` once no more frames should be written
+    fn push(&mut self, return_address: u32) -> Result<bool, fmt::Error> {
+        if self.count == BACKTRACE_DEPTH {
+            writeln!(self.f, "  ... (more frames omitted)")?;
+            return Ok(false);
+        }
+
+        writeln!(self.f, "  #{} {:#010x}", self.count, return_address)?;
+        self.count += 1;
+        Ok(true)
+    }
+
+    /// Writes a note at the end of the backtrace
+    fn note(&mut self, note: &str) -> fmt::Result {
+        writeln!(self.f, "  ({})", note)
+    }
+}
+
+/// Returns `true` if `address` lies in the `.text` section
+#[cfg(not(test))]
+fn in_text(address: u32) -> bool {
+    unsafe {
+        let start = &__stext as *const u32 as u32;
+        let end = &__etext as *const u32 as u32;
+
+        address >= start && address < end
+    }
+}
+
+/// Returns the address of `main`, the outermost function that has a frame of its own
+#[cfg(all(any(feature = "backtrace-exidx", feature = "backtrace-fp"), not(test)))]
+fn main_address() -> u32 {
+    main as unsafe extern "C" fn() -> ! as usize as u32 & !1
+}
+
+/// Reads the word at `address`
+///
+/// # Safety
+///
+/// `address` must be word aligned and point into the stack or flash
+#[cfg(not(test))]
+unsafe fn read_word(address: u32) -> u32 {
+    ptr::read_volatile(address as *const u32)
+}
+
+/// Reads the halfword at `address`
+///
+/// # Safety
+///
+/// `address` must be halfword aligned and point into flash
+#[cfg(all(any(feature = "backtrace-fp", feature = "backtrace-scan"), not(test)))]
+unsafe fn read_halfword(address: u32) -> u16 {
+    ptr::read_volatile(address as *const u16)
+}
+
+/// Call instruction, one of the instructions that set LR
+#[cfg(any(feature = "backtrace-fp", feature = "backtrace-scan"))]
+#[cfg_attr(not(feature = "backtrace-fp"), allow(dead_code))]
+enum Call {
+    /// `BL <label>`, a call to `target`
+    Bl { target: u32 },
+    /// `BLX <Rm>`, a call to the address held in a register
+    Blx,
+}
+
+/// Returns the call instruction right before `return_address`, if there's one
+#[cfg(any(feature = "backtrace-fp", feature = "backtrace-scan"))]
+fn call_before(return_address: u32) -> Option<Call> {
+    let pc = return_address & !1;
+    if !in_text(pc) || !in_text(pc.wrapping_sub(4)) {
+        return None;
+    }
+
+    // NOTE(unsafe) halfword aligned addresses within `.text`
+    let (hw1, hw2) = unsafe { (read_halfword(pc - 4), read_halfword(pc - 2)) };
+
+    // BL <label>: 11110Sii_iiiiiiii 11J1Jiii_iiiiiiii
+    if hw1 & 0xf800 == 0xf000 && hw2 & 0xd000 == 0xd000 {
+        let (hw1, hw2) = (u32::from(hw1), u32::from(hw2));
+        let s = (hw1 >> 10) & 1;
+        let i1 = !((hw2 >> 13) ^ s) & 1;
+        let i2 = !((hw2 >> 11) ^ s) & 1;
+        let offset =
+            (s << 24) | (i1 << 23) | (i2 << 22) | ((hw1 & 0x3ff) << 12) | ((hw2 & 0x7ff) << 1);
+        // sign extend from 25 bits
+        let offset = ((offset << 7) as i32 >> 7) as u32;
+
+        return Some(Call::Bl {
+            target: pc.wrapping_add(offset),
+        });
+    }
+
+    // BLX <Rm>: 01000111_1xxxx000
+    if hw2 & 0xff87 == 0x4780 {
+        return Some(Call::Blx);
+    }
+
+    None
+}
+
+/// Returns `true` if the instruction right before `return_address` is a `BL` to `main`, i.e. if
+/// `return_address` is the one of `main`'s frame, which `Reset` called
+#[cfg(feature = "backtrace-fp")]
+fn returns_from_main(return_address: u32) -> bool {
+    match call_before(return_address) {
+        Some(Call::Bl { target }) => target == main_address(),
+        _ => false,
+    }
+}
+
+/// `.text` of the tests
+#[cfg(test)]
+const TEXT: (u32, u32) = (0x0800_0000, 0x0801_0000);
+
+#[cfg(test)]
+fn in_text(address: u32) -> bool {
+    address >= TEXT.0 && address < TEXT.1
+}
+
+/// Lowest `EXC_RETURN` value, which LR holds in an exception handler
+#[cfg(any(feature = "backtrace-exidx", feature = "backtrace-fp"))]
+const EXC_RETURN: u32 = 0xffff_ff00;
+
+/// Returns the approximate value of the current stack pointer
+#[cfg(feature = "backtrace-scan")]
+#[inline(always)]
+fn stack_pointer() -> u32 {
+    let marker = 0u32;
+    &marker as *const u32 as u32
+}
+
+/// Registers needed to start unwinding the function that captures them
+#[cfg(any(feature = "backtrace-exidx", feature = "backtrace-fp"))]
+#[cfg_attr(not(feature = "backtrace-exidx"), allow(dead_code))]
+struct Registers {
+    r7: u32,
+    sp: u32,
+    lr: u32,
+    pc: u32,
+}
+
+#[cfg(any(feature = "backtrace-exidx", feature = "backtrace-fp"))]
+impl Registers {
+    /// Captures the registers; always inlined so that they describe the caller's frame
+    #[inline(always)]
+    fn capture() -> Self {
+        match () {
+            #[cfg(target_arch = "arm")]
+            () => {
+                let (r7, sp, lr, pc): (u32, u32, u32, u32);
+                // NOTE one instruction per `asm!` so that no output can clobber a register that
+                // has yet to be read
+                unsafe {
+                    asm!("mov {}, r7", out(reg) r7, options(nomem, nostack, preserves_flags));
+                    asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+                    asm!("mov {}, lr", out(reg) lr, options(nomem, nostack, preserves_flags));
+                    asm!("mov {}, pc", out(reg) pc, options(nomem, nostack, preserves_flags));
+                }
+                Registers { r7, sp, lr, pc }
+            }
+            #[cfg(not(target_arch = "arm"))]
+            () => Registers {
+                r7: 0,
+                sp: 0,
+                lr: 0,
+                pc: 0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    ::std::thread_local! {
+        /// Simulated memory: the words mapped by the test
+        static MEMORY: RefCell<Vec<(u32, u32)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// `main`; see `TEXT`
+    #[cfg(any(feature = "backtrace-exidx", feature = "backtrace-fp"))]
+    pub const MAIN: u32 = super::TEXT.0 + 0x100;
+
+    #[cfg(any(feature = "backtrace-exidx", feature = "backtrace-fp"))]
+    pub fn main_address() -> u32 {
+        MAIN
+    }
+
+    /// Replaces the simulated memory with `words`, pairs of address and value
+    pub fn map(words: Vec<(u32, u32)>) {
+        MEMORY.with(|m| *m.borrow_mut() = words);
+    }
+
+    /// Encodes a `BL` at `from` to `to` as a word
+    #[cfg(any(feature = "backtrace-fp", feature = "backtrace-scan"))]
+    pub fn bl(from: u32, to: u32) -> u32 {
+        let offset = to.wrapping_sub(from + 4);
+        let s = (offset >> 24) & 1;
+        let j1 = !(((offset >> 23) & 1) ^ s) & 1;
+        let j2 = !(((offset >> 22) & 1) ^ s) & 1;
+        let hw1 = 0xf000 | (s << 10) | ((offset >> 12) & 0x3ff);
+        let hw2 = 0xd000 | (j1 << 13) | (j2 << 11) | ((offset >> 1) & 0x7ff);
+        hw1 | (hw2 << 16)
+    }
+
+    #[cfg(any(feature = "backtrace-fp", feature = "backtrace-scan"))]
+    pub unsafe fn read_halfword(address: u32) -> u16 {
+        (read_word(address & !0b11) >> ((address & 0b10) * 8)) as u16
+    }
+
+    pub unsafe fn read_word(address: u32) -> u32 {
+        MEMORY.with(|m| {
+            m.borrow()
+                .iter()
+                .find(|&&(a, _)| a == address)
+                .map(|&(_, word)| word)
+                .unwrap_or_else(|| panic!("read from unmapped address {:#010x}", address))
+        })
+    }
+}
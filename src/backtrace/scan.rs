@@ -0,0 +1,121 @@
+//! Heuristic stack scanning
+//!
+//! Every word between the stack pointer and the top of the main stack that looks like a return
+//! address is reported. A word looks like a return address if it has the Thumb bit set, points into
+//! `.text` and the instruction right before the address it points to is a `BL` or `BLX` -- the
+//! instructions that set LR. Stale return addresses left on the stack by functions that have
+//! already returned, or data that happens to look like a return address, are reported too.
+
+use core::fmt;
+
+use super::{call_before, read_word, stack_pointer, stack_top, Frames};
+
+pub fn write(frames: &mut Frames) -> fmt::Result {
+    let top = stack_top();
+    let bottom = stack_pointer() & !0b11;
+
+    if bottom >= top {
+        return frames.note("the stack pointer is outside of the main stack");
+    }
+
+    scan(frames, bottom, top)
+}
+
+/// Writes the words between `bottom` and `top` that look like return addresses
+fn scan(frames: &mut Frames, bottom: u32, top: u32) -> fmt::Result {
+    let mut address = bottom;
+    while address < top {
+        // NOTE(unsafe) word aligned address within the stack
+        let word = unsafe { read_word(address) };
+
+        if is_return_address(word) && !frames.push(word)? {
+            break;
+        }
+
+        address += 4;
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if `word` looks like a return address
+fn is_return_address(word: u32) -> bool {
+    // the Thumb bit must be set
+    word & 1 != 0 && call_before(word).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::super::tests::{bl, map};
+    use super::super::{Frames, TEXT};
+    use super::scan;
+
+    const STACK: (u32, u32) = (0x2000_0000, 0x2000_0100);
+
+    /// Calls `CALLEE` with a `BL` right before `CALLER + 0x20`
+    const CALLER: u32 = TEXT.0 + 0x1000;
+    const CALLEE: u32 = TEXT.0 + 0x2000;
+    /// Calls through a register with a `BLX r3` right before `INDIRECT + 0x20`
+    const INDIRECT: u32 = TEXT.0 + 0x3000;
+    /// No call right before `PLAIN + 0x20`: `movs r0, r0` twice
+    const PLAIN: u32 = TEXT.0 + 0x4000;
+
+    /// Maps the test functions and the `stack` words, then scans the stack
+    fn backtrace(stack: &[u32]) -> String {
+        let mut words = Vec::new();
+        words.push((CALLER + 0x1c, bl(CALLER + 0x1c, CALLEE)));
+        // `movs r0, r0; blx r3`
+        words.push((INDIRECT + 0x1c, 0x4798_0000));
+        words.push((PLAIN + 0x1c, 0));
+        for (i, &word) in stack.iter().enumerate() {
+            words.push((STACK.0 + i as u32 * 4, word));
+        }
+        map(words);
+
+        let mut report = String::new();
+        scan(
+            &mut Frames::new(&mut report, "scan").unwrap(),
+            STACK.0,
+            STACK.0 + stack.len() as u32 * 4,
+        )
+        .unwrap();
+        report
+    }
+
+    #[test]
+    fn return_addresses() {
+        let report = backtrace(&[
+            CALLER + 0x21,
+            // saved registers
+            0,
+            0x2000_0010,
+            INDIRECT + 0x21,
+        ]);
+
+        assert_eq!(
+            report,
+            "stack backtrace (scan):\n  \
+             #0 0x08001021\n  \
+             #1 0x08003021\n"
+        );
+    }
+
+    #[test]
+    fn not_return_addresses() {
+        let report = backtrace(&[
+            // no Thumb bit
+            CALLER + 0x20,
+            // no call before it
+            PLAIN + 0x21,
+            // outside of `.text`
+            0x1234_5679,
+            // too close to the start of `.text` to follow a call
+            TEXT.0 + 3,
+        ]);
+
+        assert_eq!(report, "stack backtrace (scan):\n");
+    }
+}
@@ -0,0 +1,119 @@
+//! Static buffer the panic report is formatted into
+//!
+//! Formatting straight into a semihosting stream results in one `SYS_WRITE` call per `fmt`
+//! fragment, and every call halts the core while the debugger services it. Instead the whole report
+//! is formatted into this buffer first and then handed to the host in a single call.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+
+use config::BUFFER_SIZE;
+
+/// Marker appended to the report when it doesn't fit in the buffer
+const TRUNCATED: &str = "... (truncated)\n";
+
+struct Storage(UnsafeCell<[u8; BUFFER_SIZE]>);
+
+// NOTE(unsafe) only accessed through `Buffer::take`, whose contract rules out concurrent access
+unsafe impl Sync for Storage {}
+
+static STORAGE: Storage = Storage(UnsafeCell::new([0; BUFFER_SIZE]));
+
+/// Fixed-size formatting buffer
+pub struct Buffer {
+    bytes: &'static mut [u8; BUFFER_SIZE],
+    len: usize,
+    truncated: bool,
+}
+
+impl Buffer {
+    /// Takes the static buffer, discarding any previous contents
+    ///
+    /// # Safety
+    ///
+    /// There must be no other `Buffer` in use. This holds in the panic handler because interrupts
+    /// are disabled and a previous `Buffer` is only ever abandoned, never resumed.
+    pub unsafe fn take() -> Self {
+        Buffer {
+            bytes: &mut *STORAGE.0.get(),
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// Returns the formatted report, including the truncation marker if the report overflowed
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Copies `bytes` into the buffer; `bytes` must fit in the remaining space
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Err(fmt::Error);
+        }
+
+        // always leave enough room for the truncation marker
+        let free = BUFFER_SIZE - TRUNCATED.len() - self.len;
+        if s.len() <= free {
+            self.push(s.as_bytes());
+            return Ok(());
+        }
+
+        // don't split a multi-byte character
+        let mut end = free;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.push(&s.as_bytes()[..end]);
+        self.push(TRUNCATED.as_bytes());
+        self.truncated = true;
+
+        // stop the formatting machinery early; nothing else will fit anyway
+        Err(fmt::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::{Buffer, TRUNCATED};
+    use config::BUFFER_SIZE;
+
+    // NOTE a single test because all the buffers share the static storage
+    #[test]
+    fn truncation() {
+        // fits
+        let mut buffer = unsafe { Buffer::take() };
+        assert!(buffer.write_str("oops\n").is_ok());
+        assert_eq!(buffer.as_bytes(), b"oops\n");
+
+        // overflows
+        let mut buffer = unsafe { Buffer::take() };
+        for _ in 0..BUFFER_SIZE {
+            if buffer.write_str("é").is_err() {
+                break;
+            }
+        }
+        let bytes = buffer.as_bytes();
+        assert!(bytes.ends_with(TRUNCATED.as_bytes()));
+        // no character was split
+        let message = &bytes[..bytes.len() - TRUNCATED.len()];
+        assert!(message.chunks(2).all(|c| c == "é".as_bytes()));
+        assert!(bytes.len() > BUFFER_SIZE - TRUNCATED.len() - 2);
+        assert!(bytes.len() <= BUFFER_SIZE);
+
+        // nothing more is written after the marker
+        let len = bytes.len();
+        assert!(buffer.write_str("more").is_err());
+        assert_eq!(buffer.as_bytes().len(), len);
+    }
+}
@@ -0,0 +1,88 @@
+//! Build metadata
+//!
+//! The `build_info!` macro places `key=value` lines in the `.panic_semihosting_build_info` linker
+//! section; the linker adds the GNU build-id note to `.note.gnu.build-id` when it's passed
+//! `--build-id`. The `panic-semihosting.x` linker script fragment keeps both sections and delimits
+//! them with `__panic_semihosting_build_{info,id}_{start,end}` symbols.
+
+use core::{fmt, slice, str};
+
+extern "C" {
+    static __panic_semihosting_build_info_start: u8;
+    static __panic_semihosting_build_info_end: u8;
+    static __panic_semihosting_build_id_start: u8;
+    static __panic_semihosting_build_id_end: u8;
+}
+
+/// `NT_GNU_BUILD_ID` note type
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Writes the build metadata; writes nothing if there's none
+pub fn write(f: &mut dyn fmt::Write) -> fmt::Result {
+    // NOTE(unsafe) the linker script delimits the sections with these symbols
+    let (info, note) = unsafe {
+        (
+            section(
+                &__panic_semihosting_build_info_start,
+                &__panic_semihosting_build_info_end,
+            ),
+            section(
+                &__panic_semihosting_build_id_start,
+                &__panic_semihosting_build_id_end,
+            ),
+        )
+    };
+    let build_id = build_id(note);
+
+    if info.is_empty() && build_id.is_none() {
+        return Ok(());
+    }
+
+    writeln!(f, "build info:")?;
+    for line in info.split(|byte| *byte == b'\n') {
+        if !line.is_empty() {
+            writeln!(f, "  {}", str::from_utf8(line).unwrap_or("(invalid UTF-8)"))?;
+        }
+    }
+
+    if let Some(build_id) = build_id {
+        f.write_str("  build-id=")?;
+        for byte in build_id {
+            write!(f, "{:02x}", byte)?;
+        }
+        f.write_str("\n")?;
+    }
+
+    Ok(())
+}
+
+/// Returns the bytes between `start` and `end`
+unsafe fn section(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    let end = end as *const u8;
+    slice::from_raw_parts(start, end as usize - start as usize)
+}
+
+/// Extracts the build-id from the contents of the `.note.gnu.build-id` section
+fn build_id(note: &[u8]) -> Option<&[u8]> {
+    let word = |offset: usize| -> Option<u32> {
+        let bytes = note.get(offset..offset + 4)?;
+        Some(
+            u32::from(bytes[0])
+                | u32::from(bytes[1]) << 8
+                | u32::from(bytes[2]) << 16
+                | u32::from(bytes[3]) << 24,
+        )
+    };
+
+    // header: name size, descriptor size and type; then the name and the descriptor, both padded
+    // to a multiple of 4 bytes
+    let name_size = word(0)? as usize;
+    let desc_size = word(4)? as usize;
+    if word(8)? != NT_GNU_BUILD_ID || note.get(12..12 + name_size)? != b"GNU\0" {
+        return None;
+    }
+
+    let desc = 12 + ((name_size + 3) & !3);
+    note.get(desc..desc + desc_size)
+}
@@ -0,0 +1,47 @@
+//! The `DefaultHandler` exception handler
+
+use core::fmt::Write;
+
+use cortex_m::interrupt;
+#[cfg(target_arch = "arm")]
+use cortex_m_rt::exception;
+
+use buffer::Buffer;
+use fault;
+
+// NOTE `#[exception]` expands to ARM assembly; only the report is built for other architectures
+#[cfg(target_arch = "arm")]
+#[exception]
+unsafe fn DefaultHandler(irqn: i16) -> ! {
+    report(irqn);
+
+    match () {
+        #[cfg(feature = "exit-extended")]
+        () => ::terminate_with(::ExitReason::UnhandledException),
+        #[cfg(not(feature = "exit-extended"))]
+        () => ::terminate(),
+    }
+}
+
+/// Reports the unhandled exception `irqn`, numbered like `SCB_ICSR.VECTACTIVE` minus 16
+#[cfg_attr(not(target_arch = "arm"), allow(dead_code))]
+fn report(irqn: i16) {
+    interrupt::disable();
+
+    #[cfg(feature = "hooks")]
+    ::run_hooks();
+
+    // if the exception preempted the writing of a report to the sinks, finish that first
+    ::sink::resume();
+
+    // NOTE(unsafe) interrupts are disabled; if the exception preempted the formatting of a panic
+    // report, e.g. it's a non-maskable interrupt, that report is abandoned
+    let mut buffer = unsafe { Buffer::take() };
+    ::timestamp::write(&mut buffer).ok();
+    buffer.write_str("unhandled exception: ").ok();
+    fault::write_exception(&mut buffer, (irqn + 16) as u16).ok();
+    buffer.write_str("\n").ok();
+    ::exception::write(&mut buffer).ok();
+
+    ::sink::write(buffer.as_bytes());
+}
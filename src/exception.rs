@@ -0,0 +1,228 @@
+//! Exception frame of the interrupted context
+//!
+//! On exception entry the processor pushes R0-R3, R12, LR, PC and xPSR onto the stack that was in
+//! use, MSP or PSP, and loads LR with an `EXC_RETURN` value that tells which one it was. The handler
+//! saves that value in its prologue, at the top of its stack frame, so the innermost `EXC_RETURN`
+//! found on the main stack, above the current stack pointer, belongs to the active exception. If
+//! the interrupted context used MSP, the exception frame lies right above the saved `EXC_RETURN`.
+
+use core::{fmt, ptr};
+
+use cortex_m::peripheral::SCB;
+use cortex_m::register::psp;
+
+use fault::VECTACTIVE;
+use stack_top;
+
+/// `EXC_RETURN.SPSEL`: the frame was pushed onto PSP
+const SPSEL: u32 = 1 << 2;
+/// `EXC_RETURN.Mode`: the exception interrupted thread mode
+const MODE: u32 = 1 << 3;
+/// `EXC_RETURN.FType`: the frame doesn't include the floating point context
+const FTYPE: u32 = 1 << 4;
+/// `EXC_RETURN.DCRS`: the callee saved registers were not pushed before the frame
+#[cfg(armv8m)]
+const DCRS: u32 = 1 << 5;
+
+/// Size, in bytes, of the frame without the floating point context
+const BASIC_FRAME_SIZE: u32 = 8 * 4;
+/// Size, in bytes, of the frame with the floating point context: S0-S15, FPSCR and a reserved word
+const EXTENDED_FRAME_SIZE: u32 = BASIC_FRAME_SIZE + 18 * 4;
+/// Size, in bytes, of the additional state context (integrity signature, reserved word and R4-R11)
+#[cfg(armv8m)]
+const ADDITIONAL_STATE_SIZE: u32 = 10 * 4;
+
+/// `xPSR.T`: the Thumb state bit
+const XPSR_T: u32 = 1 << 24;
+/// `xPSR` bit 9: the stack was realigned to 8 bytes on exception entry, skipping one word
+const XPSR_ALIGNED: u32 = 1 << 9;
+/// `xPSR.IPSR`
+const XPSR_IPSR: u32 = 0x1ff;
+
+/// Hardware-stacked frame of the context interrupted by the active exception
+struct ExceptionFrame {
+    /// Address of the frame
+    base: u32,
+    /// `EXC_RETURN` value of the exception, if known
+    exc_return: Option<u32>,
+    r0: u32,
+    r1: u32,
+    r2: u32,
+    r3: u32,
+    r12: u32,
+    lr: u32,
+    pc: u32,
+    xpsr: u32,
+}
+
+/// Writes the exception frame of the interrupted context; writes nothing in thread mode
+pub fn write(f: &mut dyn fmt::Write) -> fmt::Result {
+    // NOTE(unsafe) read-only access to a register that has no side effects on read
+    let icsr = unsafe { (*SCB::ptr()).icsr.read() };
+    if icsr & VECTACTIVE == 0 {
+        return Ok(());
+    }
+
+    match ExceptionFrame::locate() {
+        Some(frame) => write!(f, "{}", frame),
+        None => writeln!(f, "exception frame:\n  (not found)"),
+    }
+}
+
+/// Writes the exception frame found at address `base`, e.g. the one `cortex-m-rt` passes to the
+/// `HardFault` handler
+///
+/// # Safety
+///
+/// `base` must point to an exception frame
+#[cfg(feature = "hard-fault")]
+pub unsafe fn write_at(f: &mut dyn fmt::Write, base: u32) -> fmt::Result {
+    write!(f, "{}", ExceptionFrame::at(base, None))
+}
+
+impl ExceptionFrame {
+    /// Looks for the innermost `EXC_RETURN` on the main stack and reads the frame it refers to
+    fn locate() -> Option<Self> {
+        let marker = 0u32;
+        let sp = &marker as *const u32 as u32;
+        let top = stack_top();
+
+        let mut slot = sp;
+        while slot < top {
+            // NOTE(unsafe) word aligned address within the main stack
+            let word = unsafe { ptr::read_volatile(slot as *const u32) };
+            if is_exc_return(word) {
+                let base = if word & SPSEL != 0 {
+                    psp::read()
+                } else {
+                    slot + 4
+                };
+
+                if let Some(frame) = Self::read(base, word, top) {
+                    return Some(frame);
+                }
+            }
+
+            slot += 4;
+        }
+
+        None
+    }
+
+    /// Reads the frame pushed at `base`; returns `None` if it doesn't look like one
+    fn read(base: u32, exc_return: u32, top: u32) -> Option<Self> {
+        #[cfg(armv8m)]
+        let base = if exc_return & DCRS == 0 {
+            base + ADDITIONAL_STATE_SIZE
+        } else {
+            base
+        };
+
+        // frames on the main stack must fit in it; there's nothing to check a PSP frame against
+        if base & 0b11 != 0 || (exc_return & SPSEL == 0 && base + BASIC_FRAME_SIZE > top) {
+            return None;
+        }
+
+        // NOTE(unsafe) word aligned address within the stack
+        let frame = unsafe { Self::at(base, Some(exc_return)) };
+
+        // the interrupted code must run in Thumb state, from a halfword aligned address, in the
+        // mode `EXC_RETURN` says
+        let thread_mode = frame.xpsr & XPSR_IPSR == 0;
+        if frame.xpsr & XPSR_T == 0 || frame.pc & 1 != 0 || thread_mode != (exc_return & MODE != 0)
+        {
+            return None;
+        }
+
+        Some(frame)
+    }
+
+    /// Reads the frame at `base`, which must be a word aligned, readable address
+    unsafe fn at(base: u32, exc_return: Option<u32>) -> Self {
+        let word = |index: u32| ptr::read_volatile((base + 4 * index) as *const u32);
+
+        ExceptionFrame {
+            base,
+            exc_return,
+            r0: word(0),
+            r1: word(1),
+            r2: word(2),
+            r3: word(3),
+            r12: word(4),
+            lr: word(5),
+            pc: word(6),
+            xpsr: word(7),
+        }
+    }
+
+    /// Returns the size of the frame, if known
+    fn size(&self) -> Option<u32> {
+        match self.exc_return {
+            Some(exc_return) if exc_return & FTYPE == 0 => Some(EXTENDED_FRAME_SIZE),
+            Some(_) => Some(BASIC_FRAME_SIZE),
+            // without a floating point unit all frames are basic
+            #[cfg(not(has_fpu))]
+            None => Some(BASIC_FRAME_SIZE),
+            #[cfg(has_fpu)]
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "exception frame:")?;
+        if let Some(exc_return) = self.exc_return {
+            writeln!(
+                f,
+                "  EXC_RETURN = {:#010x} ({}, {}, {} frame)",
+                exc_return,
+                if exc_return & MODE != 0 {
+                    "thread mode"
+                } else {
+                    "handler mode"
+                },
+                if exc_return & SPSEL != 0 {
+                    "PSP"
+                } else {
+                    "MSP"
+                },
+                if exc_return & FTYPE != 0 {
+                    "basic"
+                } else {
+                    "extended"
+                }
+            )?;
+        }
+        writeln!(f, "  R0         = {:#010x}", self.r0)?;
+        writeln!(f, "  R1         = {:#010x}", self.r1)?;
+        writeln!(f, "  R2         = {:#010x}", self.r2)?;
+        writeln!(f, "  R3         = {:#010x}", self.r3)?;
+        writeln!(f, "  R12        = {:#010x}", self.r12)?;
+        writeln!(f, "  LR         = {:#010x}", self.lr)?;
+        writeln!(f, "  PC         = {:#010x}", self.pc)?;
+        writeln!(f, "  xPSR       = {:#010x}", self.xpsr)?;
+        match self.size() {
+            Some(size) => {
+                let padding = if self.xpsr & XPSR_ALIGNED != 0 { 4 } else { 0 };
+                writeln!(f, "  SP         = {:#010x}", self.base + size + padding)
+            }
+            None => writeln!(f, "  SP         = unknown (frame type unknown)"),
+        }
+    }
+}
+
+/// Returns `true` if `word` is a valid `EXC_RETURN` value
+fn is_exc_return(word: u32) -> bool {
+    match () {
+        // bits 31:7 are ones; bits 6:0 select the security state, frame type, mode and stack
+        #[cfg(armv8m)]
+        () => word & 0xffff_ff80 == 0xffff_ff80,
+        // bits 31:5 are ones except for FType; bits 3:0 select the mode and stack
+        #[cfg(not(armv8m))]
+        () => {
+            let word = word | FTYPE;
+            word == 0xffff_fff1 || word == 0xffff_fff9 || word == 0xffff_fffd
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! System fault state
+//!
+//! ARMv6-M and ARMv8-M Baseline devices only have the ICSR register; the configurable and hard
+//! fault status registers, and the fault address registers, are not implemented.
+
+use core::fmt;
+
+use cortex_m::peripheral::SCB;
+
+/// `CFSR` bit positions and names
+#[cfg(not(any(armv6m, armv8m_base)))]
+const CFSR_BITS: &[(u32, &str)] = &[
+    // MemManage Fault Status (MMFSR)
+    (0, "IACCVIOL"),
+    (1, "DACCVIOL"),
+    (3, "MUNSTKERR"),
+    (4, "MSTKERR"),
+    (5, "MLSPERR"),
+    (7, "MMARVALID"),
+    // BusFault Status (BFSR)
+    (8, "IBUSERR"),
+    (9, "PRECISERR"),
+    (10, "IMPRECISERR"),
+    (11, "UNSTKERR"),
+    (12, "STKERR"),
+    (13, "LSPERR"),
+    (15, "BFARVALID"),
+    // UsageFault Status (UFSR)
+    (16, "UNDEFINSTR"),
+    (17, "INVSTATE"),
+    (18, "INVPC"),
+    (19, "NOCP"),
+    (20, "STKOF"),
+    (24, "UNALIGNED"),
+    (25, "DIVBYZERO"),
+];
+
+/// `HFSR` bit positions and names
+#[cfg(not(any(armv6m, armv8m_base)))]
+const HFSR_BITS: &[(u32, &str)] = &[(1, "VECTTBL"), (30, "FORCED"), (31, "DEBUGEVT")];
+
+/// `CFSR.MMARVALID`
+#[cfg(not(any(armv6m, armv8m_base)))]
+const MMARVALID: u32 = 1 << 7;
+/// `CFSR.BFARVALID`
+#[cfg(not(any(armv6m, armv8m_base)))]
+const BFARVALID: u32 = 1 << 15;
+
+/// `ICSR.VECTACTIVE`; the same value as `IPSR`
+pub const VECTACTIVE: u32 = 0x1ff;
+
+/// Snapshot of the fault state
+// NOTE `repr(C)` because it's stored in the panic record, whose layout must not change between
+// builds
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct FaultRegisters {
+    pub icsr: u32,
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub cfsr: u32,
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub hfsr: u32,
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub mmfar: u32,
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub bfar: u32,
+}
+
+impl FaultRegisters {
+    /// Reads the fault registers
+    pub fn read() -> Self {
+        // NOTE(unsafe) read-only accesses to registers that have no side effects on read
+        unsafe {
+            let scb = &*SCB::ptr();
+
+            FaultRegisters {
+                icsr: scb.icsr.read(),
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                cfsr: scb.cfsr.read(),
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                hfsr: scb.hfsr.read(),
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                mmfar: scb.mmfar.read(),
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                bfar: scb.bfar.read(),
+            }
+        }
+    }
+
+    /// Number of the active exception, or 0 in thread mode
+    pub fn active_exception(&self) -> u16 {
+        (self.icsr & VECTACTIVE) as u16
+    }
+}
+
+impl fmt::Display for FaultRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "fault registers:")?;
+        writeln!(f, "  ICSR  = {:#010x}", self.icsr)?;
+        write!(f, "  IPSR  = {:#05x} (", self.active_exception())?;
+        write_exception(f, self.active_exception())?;
+        writeln!(f, ")")?;
+
+        match () {
+            #[cfg(not(any(armv6m, armv8m_base)))]
+            () => {
+                write!(f, "  CFSR  = {:#010x}", self.cfsr)?;
+                write_bits(f, self.cfsr, CFSR_BITS)?;
+                write!(f, "  HFSR  = {:#010x}", self.hfsr)?;
+                write_bits(f, self.hfsr, HFSR_BITS)?;
+                write_address(f, "MMFAR", self.mmfar, self.cfsr & MMARVALID != 0)?;
+                write_address(f, "BFAR ", self.bfar, self.cfsr & BFARVALID != 0)
+            }
+            #[cfg(any(armv6m, armv8m_base))]
+            () => writeln!(
+                f,
+                "  CFSR, HFSR, MMFAR and BFAR are not implemented on this core"
+            ),
+        }
+    }
+}
+
+/// Writes the name of exception `number`, as found in `IPSR`
+pub fn write_exception(f: &mut dyn fmt::Write, number: u16) -> fmt::Result {
+    let name = match number {
+        0 => "thread mode",
+        1 => "Reset",
+        2 => "NMI",
+        3 => "HardFault",
+        4 => "MemManage",
+        5 => "BusFault",
+        6 => "UsageFault",
+        7 => "SecureFault",
+        11 => "SVCall",
+        12 => "DebugMonitor",
+        14 => "PendSV",
+        15 => "SysTick",
+        n if n >= 16 => return write!(f, "IRQ {}", n - 16),
+        _ => "reserved",
+    };
+
+    f.write_str(name)
+}
+
+/// Writes the names of the bits set in `value` followed by a newline
+#[cfg(not(any(armv6m, armv8m_base)))]
+fn write_bits(f: &mut fmt::Formatter, value: u32, names: &[(u32, &str)]) -> fmt::Result {
+    let mut first = true;
+    for &(bit, name) in names {
+        if value & (1 << bit) != 0 {
+            f.write_str(if first { " (" } else { " " })?;
+            f.write_str(name)?;
+            first = false;
+        }
+    }
+
+    if !first {
+        f.write_str(")")?;
+    }
+    f.write_str("\n")
+}
+
+/// Writes a fault address register, noting when its contents are stale
+#[cfg(not(any(armv6m, armv8m_base)))]
+fn write_address(f: &mut fmt::Formatter, name: &str, value: u32, valid: bool) -> fmt::Result {
+    writeln!(
+        f,
+        "  {} = {:#010x}{}",
+        name,
+        value,
+        if valid { "" } else { " (not valid)" }
+    )
+}
@@ -0,0 +1,581 @@
+//! Panic records stored in flash
+//!
+//! The flash region is split in slots, each of which holds a sequence number followed by an
+//! encoded `PanicRecord`; a slot whose sequence number reads as erased (all ones) is free. Records
+//! are written to consecutive slots, wrapping around at the end of the region, so the slot that
+//! follows the one with the newest sequence number is the next one to write. Sequence numbers are
+//! compared as serial numbers, so they keep increasing when they wrap around. A sector is only
+//! erased right before its first slot is written, so every sector is erased once per lap around
+//! the region and the records in the other sectors are kept. A region that holds no valid record
+//! is erased as a whole before the first record is written.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
+use embedded_storage::nor_flash::NorFlash;
+
+use record::{self, PanicRecord};
+
+/// Size, in bytes, of the buffer a slot is assembled in; bounds the size of a slot
+const MAX_SLOT_SIZE: usize = 512;
+
+/// Size, in bytes, of the sequence number at the start of a slot
+const SEQUENCE_SIZE: usize = 4;
+
+/// Sequence number of a free slot
+const ERASED: u32 = 0xffff_ffff;
+
+/// Ring of panic records stored in a flash region
+pub struct FlashRing<F> {
+    flash: F,
+    /// Start of the region, relative to the start of `flash`
+    offset: u32,
+    /// Number of sectors in the region
+    sectors: u32,
+    /// Size, in bytes, of a slot
+    slot_size: usize,
+    /// Number of slots in a sector
+    slots_per_sector: u32,
+}
+
+/// Error returned by [`FlashRing`](struct.FlashRing.html) operations
+#[derive(Debug)]
+pub enum FlashError<E> {
+    /// The flash operation failed
+    Flash(E),
+    /// The region is not aligned to the erase size of the flash, spans less than two sectors, or
+    /// its sectors can't hold a record
+    InvalidRegion,
+}
+
+impl<E> From<E> for FlashError<E> {
+    fn from(e: E) -> Self {
+        FlashError::Flash(e)
+    }
+}
+
+impl<F> FlashRing<F>
+where
+    F: NorFlash,
+{
+    /// Uses the `len` bytes of `flash` that start at `offset` to store panic records
+    ///
+    /// The region must be aligned to the erase size of `flash` and span at least two sectors; one
+    /// of them is erased every time the ring wraps around it. The region should not be used for
+    /// anything else; unless it already holds records, its previous contents are erased when the
+    /// first record is stored.
+    pub fn new(flash: F, offset: u32, len: u32) -> Result<Self, FlashError<F::Error>> {
+        let erase_size = F::ERASE_SIZE as u32;
+        let align = F::WRITE_SIZE.max(F::READ_SIZE).max(4);
+        let slot_size = (SEQUENCE_SIZE + record::SIZE).div_ceil(align) * align;
+
+        if offset.checked_rem(erase_size) != Some(0)
+            || len.checked_rem(erase_size) != Some(0)
+            || len / erase_size < 2
+            || offset as usize + len as usize > flash.capacity()
+            || slot_size > MAX_SLOT_SIZE
+            || slot_size > F::ERASE_SIZE
+        {
+            return Err(FlashError::InvalidRegion);
+        }
+
+        Ok(FlashRing {
+            flash,
+            offset,
+            sectors: len / erase_size,
+            slot_size,
+            slots_per_sector: erase_size / slot_size as u32,
+        })
+    }
+
+    /// Stores `record`, erasing the oldest records if there's no free slot left
+    pub fn store(&mut self, record: &PanicRecord) -> Result<(), FlashError<F::Error>> {
+        let (next, sequence) = match self.newest()? {
+            Some((index, sequence)) => ((index + 1) % self.slots(), sequence.wrapping_add(1)),
+            None => {
+                // the region holds no record, only foreign data if anything: erase the sectors that
+                // aren't erased yet; the first one is erased below
+                for sector in 1..self.sectors {
+                    let first = sector * self.slots_per_sector;
+                    for index in first..first + self.slots_per_sector {
+                        if !self.is_erased(index)? {
+                            self.erase(sector)?;
+                            break;
+                        }
+                    }
+                }
+                (0, 0)
+            }
+        };
+        // an erased sequence number would mark the slot as free
+        let sequence = if sequence == ERASED { 0 } else { sequence };
+
+        // erase a sector when the ring enters it, dropping the oldest records, or when the slot isn't
+        // free, e.g. because the region held something else before
+        let first_of_sector = next % self.slots_per_sector == 0;
+        if first_of_sector || !self.is_erased(next)? {
+            self.erase(next / self.slots_per_sector)?;
+        }
+
+        let mut buffer = [0xff; MAX_SLOT_SIZE];
+        let slot = &mut buffer[..self.slot_size];
+        slot[..SEQUENCE_SIZE].copy_from_slice(&sequence.to_le_bytes());
+        record::encode(
+            record,
+            &mut slot[SEQUENCE_SIZE..SEQUENCE_SIZE + record::SIZE],
+        );
+
+        let address = self.address(next);
+        self.flash.write(address, slot)?;
+
+        Ok(())
+    }
+
+    /// Returns the most recently stored record, if any
+    pub fn latest(&mut self) -> Result<Option<PanicRecord>, FlashError<F::Error>> {
+        let mut latest = None;
+        self.for_each(|_, record| latest = Some(*record))?;
+        Ok(latest)
+    }
+
+    /// Calls `f` with the sequence number and contents of every stored record, oldest first
+    ///
+    /// Slots whose write was interrupted, e.g. by a power loss, are skipped.
+    pub fn for_each<G>(&mut self, mut f: G) -> Result<(), FlashError<F::Error>>
+    where
+        G: FnMut(u32, &PanicRecord),
+    {
+        // the oldest record follows the newest one
+        let start = match self.newest()? {
+            Some((index, _)) => index + 1,
+            None => return Ok(()),
+        };
+
+        for i in 0..self.slots() {
+            if let Some((sequence, Some(record))) = self.read((start + i) % self.slots())? {
+                f(sequence, &record);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Erases all the stored records
+    pub fn clear(&mut self) -> Result<(), FlashError<F::Error>> {
+        let len = self.sectors * F::ERASE_SIZE as u32;
+        self.flash.erase(self.offset, self.offset + len)?;
+        Ok(())
+    }
+
+    /// Releases the flash
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    /// Returns the total number of slots
+    fn slots(&self) -> u32 {
+        self.sectors * self.slots_per_sector
+    }
+
+    /// Returns the address of slot `index`
+    fn address(&self, index: u32) -> u32 {
+        let sector = index / self.slots_per_sector;
+        let slot = index % self.slots_per_sector;
+        self.offset + sector * F::ERASE_SIZE as u32 + slot * self.slot_size as u32
+    }
+
+    /// Erases sector `sector` of the region
+    fn erase(&mut self, sector: u32) -> Result<(), F::Error> {
+        let from = self.offset + sector * F::ERASE_SIZE as u32;
+        self.flash.erase(from, from + F::ERASE_SIZE as u32)
+    }
+
+    /// Returns the index and sequence number of the slot written last; returns `None` if no slot
+    /// holds a valid record
+    ///
+    /// Slots whose write was interrupted count as written, so that they are not written again
+    /// before their sector is erased.
+    fn newest(&mut self) -> Result<Option<(u32, u32)>, F::Error> {
+        let mut newest: Option<(u32, u32)> = None;
+        let mut valid = false;
+        for index in 0..self.slots() {
+            if let Some((sequence, record)) = self.read(index)? {
+                valid |= record.is_some();
+                // `sequence` is newer if it's ahead of `newest` by less than half the range of
+                // sequence numbers; the ring holds far fewer records than that
+                if newest
+                    .map(|(_, newest)| sequence.wrapping_sub(newest) as i32 > 0)
+                    .unwrap_or(true)
+                {
+                    newest = Some((index, sequence));
+                }
+            }
+        }
+
+        Ok(if valid { newest } else { None })
+    }
+
+    /// Reads the sequence number and the record of slot `index`; returns `None` if it's free
+    ///
+    /// A slot whose write was interrupted has a sequence number but no valid record.
+    fn read(&mut self, index: u32) -> Result<Option<(u32, Option<PanicRecord>)>, F::Error> {
+        let mut buffer = [0; MAX_SLOT_SIZE];
+        let slot = &mut buffer[..self.slot_size];
+        let address = self.address(index);
+        self.flash.read(address, slot)?;
+
+        let mut sequence = [0; SEQUENCE_SIZE];
+        sequence.copy_from_slice(&slot[..SEQUENCE_SIZE]);
+        let sequence = u32::from_le_bytes(sequence);
+
+        if sequence == ERASED {
+            return Ok(None);
+        }
+
+        let record = record::decode(&slot[SEQUENCE_SIZE..SEQUENCE_SIZE + record::SIZE]);
+        Ok(Some((sequence, record)))
+    }
+
+    /// Returns `true` if all the bytes of slot `index` are erased
+    fn is_erased(&mut self, index: u32) -> Result<bool, F::Error> {
+        let mut buffer = [0; MAX_SLOT_SIZE];
+        let slot = &mut buffer[..self.slot_size];
+        let address = self.address(index);
+        self.flash.read(address, slot)?;
+
+        Ok(slot.iter().all(|byte| *byte == 0xff))
+    }
+}
+
+/// A `FlashRing` of any flash type
+trait Store: Send {
+    fn store(&mut self, record: &PanicRecord);
+}
+
+impl<F> Store for FlashRing<F>
+where
+    F: NorFlash + Send,
+{
+    fn store(&mut self, record: &PanicRecord) {
+        // there's nothing to be done about a failure at this point
+        FlashRing::store(self, record).ok();
+    }
+}
+
+/// The ring registered with `set_ring`
+static RING: Mutex<RefCell<Option<&'static mut dyn Store>>> = Mutex::new(RefCell::new(None));
+
+pub fn set_ring<F>(ring: &'static mut FlashRing<F>)
+where
+    F: NorFlash + Send + 'static,
+{
+    interrupt::free(move |cs| *RING.borrow(cs).borrow_mut() = Some(ring));
+}
+
+/// Stores `record` in the registered ring, if any
+pub fn store(record: &PanicRecord) {
+    interrupt::free(|cs| {
+        // NOTE the ring is already borrowed if storing a previous record panicked
+        if let Ok(mut ring) = RING.borrow(cs).try_borrow_mut() {
+            if let Some(ring) = ring.as_mut() {
+                ring.store(record);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{
+        ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+    };
+
+    use std::ops::Range;
+    use std::vec::Vec;
+
+    use super::{FlashError, FlashRing, MAX_SLOT_SIZE, SEQUENCE_SIZE};
+    use record::{self, PanicRecord};
+
+    const SECTOR_SIZE: usize = 1024;
+    const SECTORS: usize = 4;
+
+    /// RAM backed NOR flash that, like the real thing, can only clear bits when writing
+    struct MockFlash {
+        bytes: [u8; SECTOR_SIZE * SECTORS],
+        erases: [u32; SECTORS],
+    }
+
+    #[derive(Debug)]
+    enum MockError {
+        NotErased,
+        OutOfBounds,
+    }
+
+    impl NorFlashError for MockError {
+        fn kind(&self) -> NorFlashErrorKind {
+            match self {
+                MockError::NotErased => NorFlashErrorKind::Other,
+                MockError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            }
+        }
+    }
+
+    impl MockFlash {
+        fn erased() -> Self {
+            MockFlash {
+                bytes: [0xff; SECTOR_SIZE * SECTORS],
+                erases: [0; SECTORS],
+            }
+        }
+
+        fn range(&self, offset: u32, len: usize) -> Result<Range<usize>, MockError> {
+            let start = offset as usize;
+            if start + len > self.bytes.len() {
+                return Err(MockError::OutOfBounds);
+            }
+            Ok(start..start + len)
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = MockError;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), MockError> {
+            let range = self.range(offset, bytes.len())?;
+            bytes.copy_from_slice(&self.bytes[range]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), MockError> {
+            assert_eq!(from as usize % SECTOR_SIZE, 0);
+            assert_eq!(to as usize % SECTOR_SIZE, 0);
+
+            let range = self.range(from, (to - from) as usize)?;
+            for sector in range.start / SECTOR_SIZE..range.end / SECTOR_SIZE {
+                self.erases[sector] += 1;
+            }
+            for byte in &mut self.bytes[range] {
+                *byte = 0xff;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MockError> {
+            assert_eq!(offset as usize % Self::WRITE_SIZE, 0);
+            assert_eq!(bytes.len() % Self::WRITE_SIZE, 0);
+
+            let range = self.range(offset, bytes.len())?;
+            for (old, new) in self.bytes[range].iter_mut().zip(bytes) {
+                if *old & *new != *new {
+                    return Err(MockError::NotErased);
+                }
+                *old = *new;
+            }
+            Ok(())
+        }
+    }
+
+    fn ring() -> FlashRing<MockFlash> {
+        FlashRing::new(MockFlash::erased(), 0, (SECTOR_SIZE * SECTORS) as u32).unwrap()
+    }
+
+    fn lines(ring: &mut FlashRing<MockFlash>) -> Vec<u32> {
+        let mut lines = Vec::new();
+        ring.for_each(|_, record| lines.push(record.line()))
+            .unwrap();
+        lines
+    }
+
+    #[test]
+    fn empty() {
+        let mut ring = ring();
+
+        assert!(ring.latest().unwrap().is_none());
+        assert!(lines(&mut ring).is_empty());
+    }
+
+    #[test]
+    fn store_and_read_back() {
+        let mut ring = ring();
+        ring.store(&PanicRecord::new("oops", "src/main.rs", 20, 5))
+            .unwrap();
+
+        let record = ring.latest().unwrap().unwrap();
+        assert_eq!(record.message(), "oops");
+        assert_eq!(record.file(), "src/main.rs");
+        assert_eq!(record.line(), 20);
+        assert_eq!(record.column(), 5);
+    }
+
+    #[test]
+    fn records_are_kept_across_instances() {
+        let mut ring = ring();
+        for line in 0..3 {
+            ring.store(&PanicRecord::new("oops", "src/main.rs", line, 1))
+                .unwrap();
+        }
+
+        let mut ring = FlashRing::new(ring.free(), 0, (SECTOR_SIZE * SECTORS) as u32).unwrap();
+        assert_eq!(lines(&mut ring), [0, 1, 2]);
+    }
+
+    #[test]
+    fn wraps_around_erasing_one_sector_at_a_time() {
+        let mut ring = ring();
+        let slots = ring.slots();
+        let per_sector = ring.slots_per_sector;
+
+        // two laps plus one record, which erases the first sector a third time
+        for line in 0..2 * slots + 1 {
+            ring.store(&PanicRecord::new("oops", "src/main.rs", line, 1))
+                .unwrap();
+        }
+
+        // only the records of the first sector are lost
+        let kept = lines(&mut ring);
+        assert_eq!(kept.len() as u32, slots - per_sector + 1);
+        assert_eq!(*kept.last().unwrap(), 2 * slots);
+        assert!(kept.windows(2).all(|pair| pair[0] + 1 == pair[1]));
+
+        assert_eq!(ring.free().erases, [3, 2, 2, 2]);
+    }
+
+    #[test]
+    fn interrupted_write_is_skipped() {
+        let mut ring = ring();
+        ring.store(&PanicRecord::new("first", "src/main.rs", 1, 1))
+            .unwrap();
+
+        // a sequence number without a record, as left by a power loss in the middle of a write
+        let address = ring.address(1);
+        ring.flash.write(address, &1u32.to_le_bytes()).unwrap();
+        assert_eq!(lines(&mut ring), [1]);
+
+        ring.store(&PanicRecord::new("second", "src/main.rs", 2, 1))
+            .unwrap();
+        assert_eq!(lines(&mut ring), [1, 2]);
+        assert_eq!(ring.latest().unwrap().unwrap().message(), "second");
+    }
+
+    #[test]
+    fn foreign_data_is_erased() {
+        let mut flash = MockFlash::erased();
+        for byte in &mut flash.bytes[..] {
+            *byte = 0x5a;
+        }
+
+        let mut ring = FlashRing::new(flash, 0, (SECTOR_SIZE * SECTORS) as u32).unwrap();
+        assert!(ring.latest().unwrap().is_none());
+
+        ring.store(&PanicRecord::new("oops", "src/main.rs", 7, 1))
+            .unwrap();
+        assert_eq!(lines(&mut ring), [7]);
+    }
+
+    /// Writes slot `index` of `ring` as if it held `sequence`, followed by `record` unless its
+    /// write was interrupted
+    fn write_slot(
+        ring: &mut FlashRing<MockFlash>,
+        index: u32,
+        sequence: u32,
+        record: Option<&PanicRecord>,
+    ) {
+        let mut buffer = [0xff; MAX_SLOT_SIZE];
+        let slot = &mut buffer[..ring.slot_size];
+        slot[..SEQUENCE_SIZE].copy_from_slice(&sequence.to_le_bytes());
+        if let Some(record) = record {
+            record::encode(
+                record,
+                &mut slot[SEQUENCE_SIZE..SEQUENCE_SIZE + record::SIZE],
+            );
+        }
+        let address = ring.address(index);
+        ring.flash.write(address, slot).unwrap();
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut ring = ring();
+        let per_sector = ring.slots_per_sector;
+        let record = PanicRecord::new("oops", "src/main.rs", 100, 1);
+        write_slot(&mut ring, per_sector - 1, 0xffff_fffe, Some(&record));
+
+        // fills the second sector
+        for line in 0..per_sector {
+            ring.store(&PanicRecord::new("oops", "src/main.rs", line, 1))
+                .unwrap();
+        }
+
+        let mut sequences = Vec::new();
+        ring.for_each(|sequence, _| sequences.push(sequence))
+            .unwrap();
+        // the erased sequence number is skipped
+        assert_eq!(sequences[0], 0xffff_fffe);
+        assert_eq!(sequences[1..], *(0..per_sector).collect::<Vec<_>>());
+
+        let kept = lines(&mut ring);
+        assert_eq!(kept[0], 100);
+        assert_eq!(kept[1..], *(0..per_sector).collect::<Vec<_>>());
+
+        assert_eq!(ring.free().erases, [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn stray_sequence_number_is_erased() {
+        let mut ring = ring();
+        let per_sector = ring.slots_per_sector;
+        // a sequence number without a record in the second sector
+        write_slot(&mut ring, 2 * per_sector - 1, 0xffff_fffe, None);
+
+        // fills the first sector
+        for line in 0..per_sector {
+            ring.store(&PanicRecord::new("oops", "src/main.rs", line, 1))
+                .unwrap();
+        }
+
+        assert_eq!(lines(&mut ring), (0..per_sector).collect::<Vec<_>>());
+        // the second sector is erased along with the first, the others were already erased
+        assert_eq!(ring.free().erases, [1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn clear() {
+        let mut ring = ring();
+        ring.store(&PanicRecord::new("oops", "src/main.rs", 1, 1))
+            .unwrap();
+        ring.clear().unwrap();
+
+        assert!(ring.latest().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_regions() {
+        let invalid = |offset, len| {
+            matches!(
+                FlashRing::new(MockFlash::erased(), offset, len),
+                Err(FlashError::InvalidRegion)
+            )
+        };
+
+        // unaligned
+        assert!(invalid(1, 2 * SECTOR_SIZE as u32));
+        assert!(invalid(0, 2 * SECTOR_SIZE as u32 + 4));
+        // a single sector
+        assert!(invalid(0, SECTOR_SIZE as u32));
+        // out of bounds
+        assert!(invalid(SECTOR_SIZE as u32, (SECTOR_SIZE * SECTORS) as u32));
+    }
+}
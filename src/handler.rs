@@ -0,0 +1,92 @@
+//! The `#[panic_handler]`
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+#[cfg(feature = "hooks")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::interrupt;
+
+use buffer::Buffer;
+use Snapshot;
+
+/// Set on entry to the panic handler; finding it already set means that a panic happened while
+/// handling a previous panic
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Addresses of the `PanicInfo` and `Snapshot` of the first panic while its hooks run; a hook that
+/// panics leaves them set, and the nested panic handler reports that panic on its behalf
+#[cfg(feature = "hooks")]
+static PENDING_INFO: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "hooks")]
+static PENDING_SNAPSHOT: AtomicUsize = AtomicUsize::new(0);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let snapshot = Snapshot::capture();
+
+    interrupt::disable();
+
+    // NOTE a load followed by a store is enough because interrupts are disabled; ARMv6-M has no
+    // atomic swap anyway
+    let nested = PANICKING.load(Ordering::Relaxed);
+    PANICKING.store(true, Ordering::Relaxed);
+
+    #[cfg(feature = "hooks")]
+    {
+        if !nested {
+            PENDING_INFO.store(info as *const PanicInfo as usize, Ordering::Relaxed);
+            PENDING_SNAPSHOT.store(&snapshot as *const Snapshot as usize, Ordering::Relaxed);
+        }
+
+        ::run_hooks();
+    }
+
+    if nested {
+        // a hook of the first panic panicked; the frame of the first panic handler, which holds
+        // its `PanicInfo` and `Snapshot`, is still live because the panic handler never returns
+        #[cfg(feature = "hooks")]
+        {
+            let info = PENDING_INFO.load(Ordering::Relaxed);
+            let snapshot = PENDING_SNAPSHOT.load(Ordering::Relaxed);
+            if info != 0 {
+                PENDING_INFO.store(0, Ordering::Relaxed);
+                // NOTE(unsafe) see above
+                unsafe {
+                    ::report_from(
+                        &*(info as *const PanicInfo),
+                        &*(snapshot as *const Snapshot),
+                    )
+                };
+            }
+        }
+
+        report_nested(info);
+    } else {
+        #[cfg(feature = "hooks")]
+        PENDING_INFO.store(0, Ordering::Relaxed);
+
+        ::report_from(info, &snapshot);
+    }
+
+    ::terminate()
+}
+
+/// Reports a nested panic without formatting its message, which may well panic again
+fn report_nested(info: &PanicInfo) {
+    // the interrupted report, if it was being written to the sinks, goes out first
+    ::sink::resume();
+
+    // NOTE(unsafe) interrupts are disabled; the buffer of the interrupted report is abandoned
+    let mut buffer = unsafe { Buffer::take() };
+
+    // NOTE no timestamp: a user `Clock` that panics would be called again on every re-entry
+    buffer.write_str("panicked while panicking").ok();
+    if let Some(location) = info.location() {
+        write!(buffer, " at {}", location).ok();
+    }
+    buffer.write_str("\n").ok();
+
+    ::sink::write(buffer.as_bytes());
+}
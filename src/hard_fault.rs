@@ -0,0 +1,48 @@
+//! The `HardFault` exception handler
+
+use core::fmt::Write;
+
+use cortex_m::interrupt;
+#[cfg(target_arch = "arm")]
+use cortex_m_rt::exception;
+use cortex_m_rt::ExceptionFrame;
+
+use buffer::Buffer;
+use fault::FaultRegisters;
+
+// NOTE `#[exception]` expands to ARM assembly; only the report is built for other architectures
+#[cfg(target_arch = "arm")]
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    report(frame);
+
+    match () {
+        #[cfg(feature = "exit-extended")]
+        () => ::terminate_with(::ExitReason::HardFault),
+        #[cfg(not(feature = "exit-extended"))]
+        () => ::terminate(),
+    }
+}
+
+/// Reports the hard fault that pushed `frame`
+#[cfg_attr(not(target_arch = "arm"), allow(dead_code))]
+fn report(frame: &ExceptionFrame) {
+    interrupt::disable();
+
+    #[cfg(feature = "hooks")]
+    ::run_hooks();
+
+    // if the fault happened while a report was being written to the sinks, finish that first
+    ::sink::resume();
+
+    // NOTE(unsafe) interrupts are disabled and only NMI can preempt this handler; if the fault
+    // happened while a panic was being formatted that report is abandoned
+    let mut buffer = unsafe { Buffer::take() };
+    ::timestamp::write(&mut buffer).ok();
+    buffer.write_str("hard fault\n").ok();
+    // NOTE(unsafe) `cortex-m-rt` passes the frame pushed on exception entry
+    unsafe { ::exception::write_at(&mut buffer, frame as *const ExceptionFrame as u32).ok() };
+    write!(buffer, "{}", FaultRegisters::read()).ok();
+
+    ::sink::write(buffer.as_bytes());
+}
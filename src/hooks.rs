@@ -0,0 +1,38 @@
+//! Safe-state hooks
+//!
+//! Hooks are `fn()` items placed in the `.panic_semihosting_hooks` linker section by the
+//! `panic_hook!` macro. The `panic-semihosting.x` linker script fragment collects them into a
+//! table delimited by the `__panic_semihosting_hooks_{start,end}` symbols.
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+extern "C" {
+    static __panic_semihosting_hooks_start: u32;
+    static __panic_semihosting_hooks_end: u32;
+}
+
+/// Index of the next hook to run
+///
+/// The index is advanced *before* a hook is called so that, if the hook panics, the re-entered
+/// panic handler resumes with the hook that follows it instead of calling the faulty hook again.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs, in link order, all the hooks that haven't run yet
+pub fn run() {
+    unsafe {
+        let start = &__panic_semihosting_hooks_start as *const u32 as *const fn();
+        let end = &__panic_semihosting_hooks_end as *const u32 as *const fn();
+        let len = (end as usize - start as usize) / mem::size_of::<fn()>();
+
+        loop {
+            let i = NEXT.load(Ordering::Relaxed);
+            if i >= len {
+                break;
+            }
+            NEXT.store(i + 1, Ordering::Relaxed);
+
+            (*start.add(i))();
+        }
+    }
+}
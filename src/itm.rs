@@ -0,0 +1,102 @@
+//! Sink that writes the reports to an ITM stimulus port
+
+use cortex_m::peripheral::itm::Stim;
+use cortex_m::peripheral::ITM;
+
+use sink::{PanicSink, SinkError};
+
+#[cfg(any(armv6m, armv8m_base))]
+compile_error!(
+    "the `itm` feature is not available on ARMv6-M and ARMv8-M Baseline: they have no ITM"
+);
+
+/// Number of stimulus ports
+const PORTS: u8 = 32;
+
+/// `ITM_TCR.ITMENA`
+const ITMENA: u32 = 1 << 0;
+
+/// Default number of times the FIFO-ready flag is polled before a write is abandoned
+const DEFAULT_SPIN_LIMIT: u32 = 100_000;
+
+/// [`PanicSink`](trait.PanicSink.html) that writes the reports to an ITM stimulus port, which the
+/// probe forwards over SWO
+///
+/// Every write waits for the stimulus port to be ready with a bounded spin; if the port doesn't
+/// become ready, e.g. because the trace clock is off or no probe is draining the trace output,
+/// the rest of the report is dropped instead of hanging the panic handler.
+pub struct ItmSink {
+    port: u8,
+    spin_limit: u32,
+}
+
+impl ItmSink {
+    /// Creates a sink that writes to stimulus port `port`, in the range 0 to 31
+    pub const fn new(port: u8) -> Self {
+        assert!(port < PORTS, "there are only 32 stimulus ports");
+
+        ItmSink {
+            port,
+            spin_limit: DEFAULT_SPIN_LIMIT,
+        }
+    }
+
+    /// Sets the number of times the FIFO-ready flag is polled before a write is abandoned
+    pub const fn spin_limit(mut self, spin_limit: u32) -> Self {
+        self.spin_limit = spin_limit;
+        self
+    }
+
+    /// Returns the stimulus port
+    fn stim(&self) -> &'static mut Stim {
+        // NOTE(unsafe) the panic handler runs with interrupts disabled and doesn't return; any
+        // write to this port that it interrupted is never resumed
+        unsafe { &mut (*ITM::PTR).stim[usize::from(self.port)] }
+    }
+
+    /// Waits, for a bounded number of polls, until `stim` can accept more data
+    fn wait(&self, stim: &Stim) -> Result<(), SinkError> {
+        for _ in 0..self.spin_limit {
+            if stim.is_fifo_ready() {
+                return Ok(());
+            }
+        }
+
+        Err(SinkError)
+    }
+}
+
+impl PanicSink for ItmSink {
+    /// The ITM and the stimulus port must be enabled
+    fn available(&self) -> bool {
+        // NOTE(unsafe) read-only accesses to registers that have no side effects on read
+        let (tcr, ter) = unsafe {
+            let itm = &*ITM::PTR;
+            (itm.tcr.read(), itm.ter[0].read())
+        };
+
+        tcr & ITMENA != 0 && ter & 1 << self.port != 0
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SinkError> {
+        let stim = self.stim();
+
+        // whole words first, as they take a quarter of the trace packets
+        let mut words = bytes.chunks_exact(4);
+        for word in &mut words {
+            self.wait(stim)?;
+            stim.write_u32(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        }
+
+        for byte in words.remainder() {
+            self.wait(stim)?;
+            stim.write_u8(*byte);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.wait(self.stim())
+    }
+}
@@ -36,8 +36,115 @@
 //! panicked at 'FOO', src/main.rs:6:5
 //! ```
 //!
+//! # Output buffering
+//!
+//! The panic message is formatted into a static buffer and then written to the host with a single
+//! semihosting call, instead of one call per formatting fragment. The size of the buffer defaults to
+//! 1024 bytes for the message, plus room for the sections added by the enabled features: 256
+//! bytes for `build-info`, 384 bytes each for `core-registers`, `exception-frame` and
+//! `fault-registers`, and 64 bytes plus 18 bytes per frame (see
+//! `PANIC_SEMIHOSTING_BACKTRACE_DEPTH`) for each backtrace feature. It can be changed by setting
+//! the `PANIC_SEMIHOSTING_BUFFER_SIZE` environment variable when building this crate; the minimum
+//! is 64 bytes.
+//!
+//! A report that doesn't fit in the buffer, e.g. because of a long message, is cut short and ends
+//! with a `... (truncated)` marker. The backtrace comes last in the report, after the build
+//! info and the register dumps, so it's the first thing to be lost.
+//!
+//! # Output sinks
+//!
+//! The report is written to the host through semihosting by default. More [`PanicSink`]s, e.g. an
+//! RTT channel or a UART, can be registered with [`add_sink`]; every report is then written to
+//! each available sink in turn, semihosting first, and a sink that fails, or even panics, doesn't
+//! keep the report from reaching the others. The semihosting sink can be disabled with
+//! [`set_semihosting_sink`].
+//!
+//! [`PanicSink`]: trait.PanicSink.html
+//! [`add_sink`]: fn.add_sink.html
+//! [`set_semihosting_sink`]: fn.set_semihosting_sink.html
+//!
+//! ``` ignore
+//! struct Uart(/* .. */);
+//!
+//! impl PanicSink for Uart {
+//!     fn write(&mut self, bytes: &[u8]) -> Result<(), SinkError> {
+//!         for byte in bytes {
+//!             // blocking write with a timeout; interrupts are disabled
+//!             self.0.write_byte(*byte).map_err(|_| SinkError)?;
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! #[entry]
+//! fn main() -> ! {
+//!     let uart: &'static mut _ = cortex_m::singleton!(: Uart = Uart(/* .. */)).unwrap();
+//!     panic_semihosting::add_sink(uart).ok();
+//!     // ..
+//! }
+//! ```
+//!
 //! # Optional features
 //!
+//! ## `panic-handler` (enabled by default)
+//!
+//! Provides the `#[panic_handler]`. Disable this feature to use this crate as a library from your
+//! own panic handler, for example to put the hardware in a safe state before logging the message:
+//!
+//! ``` ignore
+//! #![no_std]
+//!
+//! extern crate panic_semihosting;
+//!
+//! use core::panic::PanicInfo;
+//!
+//! #[panic_handler]
+//! fn panic(info: &PanicInfo) -> ! {
+//!     cortex_m::interrupt::disable();
+//!
+//!     motors::stop();
+//!
+//!     panic_semihosting::report(info);
+//!     panic_semihosting::terminate()
+//! }
+//! ```
+//!
+//! If the panic handler is re-entered, because formatting the panic message or a hook panicked, it
+//! doesn't try to format the new panic message. Instead it reports `panicked while panicking` along
+//! with the location of the second panic and goes straight to its terminal action.
+//!
+//! ## `alloc`
+//!
+//! Provides an allocation error handler, for applications that use the `alloc` crate, which
+//! reports the size and alignment of the allocation that failed, and then performs the same
+//! terminal action as the panic handler. Heap usage statistics, e.g. from the allocator, can be
+//! appended to the report by registering a hook with
+//! [`set_allocator_stats_hook`](fn.set_allocator_stats_hook.html).
+//!
+//! ``` ignore
+//! static HEAP: Heap = Heap::empty();
+//!
+//! fn heap_stats() -> AllocatorStats {
+//!     AllocatorStats {
+//!         used: HEAP.used(),
+//!         free: HEAP.free(),
+//!     }
+//! }
+//!
+//! #[entry]
+//! fn main() -> ! {
+//!     panic_semihosting::set_allocator_stats_hook(heap_stats);
+//!     // ..
+//! }
+//! ```
+//!
+//! ``` text
+//! memory allocation of 4096 bytes (alignment 4) failed
+//! heap: 1020 bytes used, 3076 bytes free
+//! ```
+//!
+//! The `#[alloc_error_handler]` attribute is unstable, so this feature requires nightly.
+//!
 //! ## `exit`
 //!
 //! When this feature is enabled the panic handler performs an exit semihosting call after logging
@@ -48,6 +155,424 @@
 //! We discourage using this feature when the program will run on hardware as the exit call can
 //! leave the hardware debugger in an inconsistent state.
 //!
+//! The terminal action can also be selected at runtime, which lets the same firmware image exit
+//! QEMU and stop at a breakpoint on hardware. The selection, in order of precedence:
+//!
+//! - [`set_terminal_action`](fn.set_terminal_action.html), or a debugger script or loader that
+//!   pokes the unmangled `PANIC_SEMIHOSTING_ACTION` word: `0x50534101` selects a breakpoint and
+//!   `0x50534102` an exit call. Any other value, like the contents of RAM after a power-on reset,
+//!   defers to the next item.
+//! - A `--panic-action=breakpoint` or `--panic-action=exit` argument on the command line passed to
+//!   the program by the host, with the `cmdline` feature.
+//! - Whether this `exit` feature is enabled.
+//!
+//! The `PANIC_SEMIHOSTING_ACTION` word is placed in the `.uninit` section of `cortex-m-rt`'s linker
+//! script (v0.7 and newer), which is not initialized on boot, so it can be written before the
+//! program starts, and it keeps its value across resets that don't power cycle the RAM. With QEMU
+//! the address to pass to the loader is the one of the symbol, e.g. as printed by `nm`:
+//!
+//! ``` text
+//! (gdb) set *(unsigned int *)&PANIC_SEMIHOSTING_ACTION = 0x50534102
+//! $ qemu-system-arm (..) -device loader,addr=0x20000040,data=0x50534102,data-len=4
+//! ```
+//!
+//! ## `exit-extended`
+//!
+//! Implies `exit`. The exit is performed with the `SYS_EXIT_EXTENDED` semihosting call, which lets
+//! the panic handler report a specific exit code to the host instead of a generic failure. The
+//! default exit code is 1; it can be changed by setting the `PANIC_SEMIHOSTING_EXIT_CODE`
+//! environment variable when building this crate, and at runtime with
+//! [`set_exit_code`](fn.set_exit_code.html). QEMU supports `SYS_EXIT_EXTENDED` since v4.0.
+//!
+//! The other handlers provided by this crate report their own exit codes, so that the host can
+//! tell the failures apart: 2 for an allocation error, 3 for a hard fault and 4 for an unhandled
+//! exception. These can be changed at runtime with
+//! [`set_exit_code_for`](fn.set_exit_code_for.html).
+//!
+//! ## `build-info`
+//!
+//! Appends the build metadata of the application to the panic report, so that reports from a fleet
+//! running different firmware revisions can be told apart:
+//!
+//! - the package name, version, profile and, optionally, git hash embedded with the
+//!   [`build_info!`](macro.build_info.html) macro, and
+//! - the GNU build-id of the ELF, if the linker was passed `--build-id`.
+//!
+//! ``` text
+//! panicked at 'FOO', src/main.rs:6:5
+//! build info:
+//!   name=app
+//!   version=0.1.0
+//!   git=1a2b3c4d
+//!   profile=release
+//!   build-id=8f3a21c55e0b7d4e2d1c9a06b3f4e5d6c7b8a901
+//! ```
+//!
+//! Both are kept in flash, in the `.panic_semihosting_build_info` and `.note.gnu.build-id`
+//! sections, by the `panic-semihosting.x` linker script (see the `hooks` feature), which must be
+//! passed to the linker. Host tools can read the sections from the ELF; the
+//! `panic-semihosting-symbolizer` warns when the build-id in a report doesn't match the ELF's.
+//! This feature requires Rust 1.51 or newer.
+//!
+//! ## `cmdline`
+//!
+//! When this feature is enabled the panic handler reads the command line of the program with the
+//! `SYS_GET_CMDLINE` semihosting call and looks for a `--panic-action=breakpoint` or
+//! `--panic-action=exit` argument. The command line is read at most once, and only when a debugger
+//! is attached and the semihosting sink hasn't been disabled with
+//! [`set_semihosting_sink`](fn.set_semihosting_sink.html). With QEMU the argument is passed like
+//! this:
+//!
+//! ``` text
+//! $ qemu-system-arm (..) -semihosting-config enable=on,target=native,arg=app,arg=--panic-action=exit
+//! ```
+//!
+//! The command line is read into a 128-byte buffer. The host refuses to return a longer command
+//! line, in which case the argument is ignored and the default terminal action is used.
+//!
+//! ## `backtrace-scan`
+//!
+//! Appends a backtrace to the panic report. The backtrace is found by scanning the current stack,
+//! from the stack pointer up to the top of the main stack, for words that look like return addresses
+//! into `.text`, using the `__stext`, `__etext` and `_stack_start` symbols provided by
+//! `cortex-m-rt`'s linker script. The addresses are printed in hex, so they can be symbolized on the
+//! host:
+//!
+//! ``` text
+//! stack backtrace (scan):
+//!   #0 0x08000b4b
+//!   #1 0x08000a0f
+//!   #2 0x080004c5
+//! ```
+//!
+//! This is a heuristic: stale return addresses of functions that already returned, and data that
+//! happens to look like a return address, show up in the backtrace as well.
+//!
+//! At most 32 frames are printed; the limit, which also applies to the other backtrace features,
+//! can be changed by setting the `PANIC_SEMIHOSTING_BACKTRACE_DEPTH` environment variable when
+//! building this crate.
+//!
+//! The `panic-semihosting-symbolizer` host tool, in the `symbolizer` directory of this crate's
+//! repository, rewrites the addresses in a backtrace into function names and source locations
+//! using the debug information of the program. Unlike this crate, it requires Rust 1.65 or newer.
+//! It's a Cargo workspace of its own; install it with `cargo install --path symbolizer`.
+//!
+//! ``` text
+//! $ panic-semihosting-symbolizer target/thumbv7m-none-eabi/debug/app openocd.log
+//! ```
+//!
+//! ## `backtrace-exidx`
+//!
+//! Appends a precise backtrace to the panic report, from the panic handler up to `Reset`, computed
+//! by interpreting the ARM exception handling ABI unwind tables (`.ARM.exidx` and `.ARM.extab`).
+//! This works without frame pointers, but the unwind tables must be emitted and kept:
+//!
+//! - pass `-C force-unwind-tables=yes` to rustc, as the tables are not emitted by default with
+//!   `panic = "abort"`, and
+//! - link with a copy of `cortex-m-rt`'s `link.x` whose `/DISCARD/` section doesn't list the
+//!   `.ARM.exidx` and `.ARM.extab` input sections; `rust-lld` drops the unwind index whenever
+//!   `/DISCARD/` matches it, which no linker script fragment can undo, and
+//! - pass the `panic-semihosting-exidx.x` linker script, which this crate puts in the linker
+//!   search path, to the linker after the copy of `link.x`; it places the tables in flash and
+//!   defines the symbols the unwinder uses to find them.
+//!
+//! ``` text
+//! # .cargo/config
+//! [target.thumbv7m-none-eabi]
+//! rustflags = [
+//!   "-C", "force-unwind-tables=yes",
+//!   "-C", "link-arg=-Tlink-exidx.x", # `link.x` without the discarded `.ARM.ex*` sections
+//!   "-C", "link-arg=-Tpanic-semihosting-exidx.x",
+//! ]
+//! ```
+//!
+//! Functions marked as not unwindable, like the ones of the precompiled `core`, are unwound
+//! through their frame record instead, which Thumb code keeps even without
+//! `-C force-frame-pointers=yes`. Unwinding ends after `main`, whose table entry is found through
+//! its symbol, which `cortex-m-rt`'s `#[entry]` provides; the last frame is the return address into
+//! `Reset`. Every read from the stack and the tables is bounds checked; unwinding stops, with a
+//! note, at the first function without usable unwind information, at a return address outside of
+//! `.text`, which means the stack is corrupted, and at the entry of an exception handler. Reading
+//! the initial registers uses `asm!`, so this feature requires Rust 1.59 or newer.
+//!
+//! ## `backtrace-fp`
+//!
+//! Appends a backtrace to the panic report computed by walking the chain of frame records, which
+//! is much cheaper than interpreting the unwind tables. The program must be compiled with
+//! `-C force-frame-pointers=yes`. The chain ends at `main`, whose caller, `Reset`, has no frame
+//! record; `main`'s record is the one whose return address follows a `BL` to the `main` symbol
+//! provided by `cortex-m-rt`'s `#[entry]`. Every frame record is checked to lie within the stack,
+//! above the previous one, and to hold a return address into `.text`; if one doesn't, the backtrace
+//! ends with a `(frame pointer chain broken)` marker.
+//! Like `backtrace-exidx`, this feature requires Rust 1.59 or newer.
+//!
+//! ## `core-registers`
+//!
+//! Appends a snapshot of the core registers to the panic report: MSP, PSP, CONTROL (privilege level
+//! and active stack pointer), PRIMASK, BASEPRI and FAULTMASK, as they were before the panic handler
+//! disabled interrupts, and the link register (LR) of the handler's caller. ARMv6-M and ARMv8-M
+//! Baseline cores don't have BASEPRI and FAULTMASK.
+//!
+//! ``` text
+//! core registers:
+//!   MSP       = 0x20007f60
+//!   PSP       = 0x00000000
+//!   CONTROL   = 0x00000000 (privileged, MSP)
+//!   PRIMASK   = 0
+//!   BASEPRI   = 0x00
+//!   FAULTMASK = 0
+//!   LR        = 0x08000b4b
+//! ```
+//!
+//! When `report` is called from a custom panic handler the registers are read at that point.
+//! Reading LR uses `asm!`, so this feature requires Rust 1.59 or newer.
+//!
+//! ## `exception-frame`
+//!
+//! When the panic happens in an exception handler, appends to the panic report the exception frame
+//! of the interrupted context: the registers the processor pushed onto the stack on exception entry
+//! (R0-R3, R12, LR, PC and xPSR), the `EXC_RETURN` value, which tells the interrupted mode, the
+//! stack the frame was pushed onto and whether it includes the floating point context, and the
+//! stack pointer of the interrupted context.
+//!
+//! ``` text
+//! exception frame:
+//!   EXC_RETURN = 0xfffffff9 (thread mode, MSP, basic frame)
+//!   R0         = 0x00000000
+//!   R1         = 0x20000010
+//!   R2         = 0x00000001
+//!   R3         = 0x40011000
+//!   R12        = 0x00000000
+//!   LR         = 0x08000b4b
+//!   PC         = 0x08000b52
+//!   xPSR       = 0x61000000
+//!   SP         = 0x20007fe0
+//! ```
+//!
+//! The frame is located by looking for the `EXC_RETURN` value the exception handler saved on the
+//! main stack, so the handler must save it, as all handlers that call other functions do. Nothing is
+//! printed in thread mode.
+//!
+//! ## `default-handler`
+//!
+//! Provides a `DefaultHandler`, through `cortex-m-rt`'s `#[exception]` attribute, that reports
+//! exceptions and interrupts that have no handler of their own: the exception number, read from
+//! IPSR, and the exception frame of the interrupted context (see the `exception-frame` feature),
+//! which includes the interrupted PC. Like the panic handler, it runs the safe-state hooks (see
+//! the `hooks` feature) before the report and then performs the same terminal action. The
+//! application must not define its own `DefaultHandler` when this feature is enabled.
+//!
+//! ``` text
+//! unhandled exception: IRQ 5
+//! exception frame:
+//!   EXC_RETURN = 0xfffffff9 (thread mode, MSP, basic frame)
+//!   ...
+//!   PC         = 0x08000b52
+//!   ...
+//! ```
+//!
+//! ## `detect-debugger`
+//!
+//! Without a debugger attached, semihosting calls and breakpoints hard-fault or lock up the
+//! microcontroller. When this feature is enabled the panic handler first checks whether a debugger
+//! is attached, using the C_DEBUGEN bit of the DHCSR register. If it isn't, the report is still
+//! written to the sinks registered with [`add_sink`](fn.add_sink.html) and saved in the panic
+//! records (see the `panic-record` and `flash-record` features), but the semihosting sink and the
+//! terminal action (breakpoint or exit) are skipped: the panic handler goes into an infinite loop
+//! instead.
+//!
+//! Note that on ARMv6-M software access to the DHCSR register is implementation defined; on some
+//! devices, e.g. Cortex-M0+, the debugger will never be detected.
+//!
+//! ## `reset-without-debugger`
+//!
+//! Implies `detect-debugger`. Instead of going into an infinite loop when no debugger is attached
+//! the panic handler resets the system.
+//!
+//! ## `fault-registers`
+//!
+//! Appends the system fault state to the panic report: the ICSR register and the active exception
+//! number (IPSR), which tell whether the panic happened inside an exception handler, and the CFSR,
+//! HFSR, MMFAR and BFAR registers. The CFSR and HFSR bits are decoded into their names.
+//!
+//! ``` text
+//! panicked at 'FOO', src/main.rs:6:5
+//! fault registers:
+//!   ICSR  = 0x00000803
+//!   IPSR  = 0x003 (HardFault)
+//!   CFSR  = 0x00008200 (PRECISERR BFARVALID)
+//!   HFSR  = 0x40000000 (FORCED)
+//!   MMFAR = 0xe000ed34 (not valid)
+//!   BFAR  = 0x30000000
+//! ```
+//!
+//! ARMv6-M and ARMv8-M Baseline cores only implement ICSR; on those the other registers are
+//! reported as not implemented.
+//!
+//! ## `hard-fault`
+//!
+//! Provides a `HardFault` exception handler, through `cortex-m-rt`'s `#[exception]` attribute, that
+//! reports the fault to the host, with the exception frame of the faulting context and the fault
+//! registers (see the `exception-frame` and `fault-registers` features), and then performs the same
+//! terminal action as the panic handler. The application must not define its own `HardFault`
+//! handler when this feature is enabled.
+//!
+//! ``` text
+//! hard fault
+//! exception frame:
+//!   R0         = 0x00000000
+//!   ...
+//!   PC         = 0x08000b52
+//!   xPSR       = 0x61000000
+//!   SP         = 0x20007fe0
+//! fault registers:
+//!   ICSR  = 0x00000803
+//!   ...
+//! ```
+//!
+//! ## `hooks`
+//!
+//! Lets the application register safe-state hooks with the [`panic_hook!`](macro.panic_hook.html)
+//! macro. The panic handler runs the hooks after masking interrupts and before writing anything to
+//! the host, so they can, for example, de-energize outputs. If a hook panics the remaining hooks
+//! still run; the original panic is then reported, followed by a `panicked while panicking` line
+//! with the location of the hook's panic. With the `hard-fault`, `default-handler` and `alloc`
+//! features the hooks also run before a hard fault, an unhandled exception or an allocation error
+//! is reported.
+//!
+//! The hooks are collected by the `panic-semihosting.x` linker script fragment, which this crate
+//! puts in the linker search path. Pass it to the linker after `cortex-m-rt`'s `link.x`:
+//!
+//! ``` text
+//! # .cargo/config
+//! [target.thumbv7m-none-eabi]
+//! rustflags = ["-C", "link-arg=-Tlink.x", "-C", "link-arg=-Tpanic-semihosting.x"]
+//! ```
+//!
+//! The `panic_hook!` macro expands to an unnamed `const _` item, so this feature requires Rust 1.37
+//! or newer.
+//!
+//! ## `timestamp-sys-clock`, `timestamp-sys-time`, `timestamp-dwt` and `timestamp-user`
+//!
+//! These features prefix the panic report, and the reports of the other handlers provided by this
+//! crate, with timestamps from the selected sources:
+//!
+//! - `timestamp-sys-clock`: the `SYS_CLOCK` semihosting call, the time since the program started
+//!   in hundredths of a second, e.g. `[12.34s]`.
+//! - `timestamp-sys-time`: the `SYS_TIME` semihosting call, the host time in seconds since the Unix
+//!   epoch, e.g. `[unix time 1700000000]`.
+//! - `timestamp-dwt`: the DWT cycle counter, e.g. `[51234567 cycles]`. The application must enable
+//!   the counter; the timestamp is left out while it's disabled. ARMv6-M and ARMv8-M Baseline cores
+//!   don't have a cycle counter.
+//! - `timestamp-user`: a user provided [`Clock`](trait.Clock.html), registered at runtime with
+//!   [`set_clock`](fn.set_clock.html), e.g. `[12.345678s]`.
+//!
+//! When several features are enabled the timestamps are written in the order listed above. The
+//! semihosting sources are only queried when a debugger is attached and the semihosting sink is
+//! enabled. The `panicked while panicking` line of a nested panic has no timestamp, so that a
+//! clock that panics can't make the panic handler recurse.
+//!
+//! ``` text
+//! [12.34s] [51234567 cycles] panicked at 'oops', src/main.rs:20:5
+//! ```
+//!
+//! ## `panic-record`
+//!
+//! Stores a compact record of the panic, which survives resets, in RAM: the message (truncated to
+//! 128 bytes), the file (its last 64 bytes), line and column of the panic, and the fault
+//! registers. This happens whether or not a debugger is attached, so a panic that was never
+//! reported, e.g. because the debugger was detached, or that was followed by a watchdog reset can
+//! still be retrieved after the next boot with [`panic_record`](fn.panic_record.html):
+//!
+//! ``` ignore
+//! #[entry]
+//! fn main() -> ! {
+//!     if let Some(record) = panic_semihosting::panic_record() {
+//!         // e.g. log `record.message()` and `record.file()`, or print it with `{}`
+//!         panic_semihosting::clear_panic_record();
+//!     }
+//!     // ..
+//! }
+//! ```
+//!
+//! [`report_previous_boot`](fn.report_previous_boot.html) does the above for you: it reports the
+//! stored panic to the host, in the same format as a live panic, and clears it. It must be called
+//! from `main`, or later, not from a `#[pre_init]` function.
+//!
+//! ``` text
+//! previous boot:
+//! panicked at src/main.rs:20:5:
+//! oops
+//! fault registers:
+//!   ICSR  = 0x00000000
+//!   ...
+//! ```
+//!
+//! The record is placed in the `.uninit` section of `cortex-m-rt`'s linker script (v0.7 and
+//! newer), which is not initialized on boot, and is validated with a magic number and a CRC. Every
+//! panic replaces the previous record. Reading the message requires Rust 1.81 or newer.
+//!
+//! ## `flash-record`
+//!
+//! Also stores the panic record in a flash region reserved for it, through the
+//! [`embedded-storage`] `NorFlash` trait, so it survives power cycles. The region holds a ring of
+//! records: every panic is written to the next free slot and, once the region is full, the sector
+//! that holds the oldest records is erased. Sectors are erased one at a time, in order, so the wear
+//! is spread evenly over the region and the records in the other sectors are kept.
+//!
+//! [`embedded-storage`]: https://crates.io/crates/embedded-storage
+//!
+//! ``` ignore
+//! #[entry]
+//! fn main() -> ! {
+//!     let flash: Flash = /* the HAL's flash driver */;
+//!     // the last two 2 KiB sectors of the flash
+//!     let ring = FlashRing::new(flash, 0x3_f000, 0x1000).unwrap();
+//!     let ring: &'static mut _ = cortex_m::singleton!(: FlashRing<Flash> = ring).unwrap();
+//!
+//!     // e.g. log the records of the previous panics, oldest first
+//!     ring.for_each(|sequence, record| { /* .. */ }).ok();
+//!     if let Ok(Some(record)) = ring.latest() { /* .. */ }
+//!
+//!     panic_semihosting::set_flash_ring(ring);
+//!     // ..
+//! }
+//! ```
+//!
+//! The region must be aligned to the erase size of the flash and span at least two sectors. The
+//! record is written after the panic has been reported to the sinks, with interrupts disabled; the
+//! flash driver must not rely on interrupts and must not panic. A record whose write was
+//! interrupted, e.g. by a power loss, is detected and skipped.
+//!
+//! ## `itm`
+//!
+//! Provides [`ItmSink`](struct.ItmSink.html), a sink that writes the reports to an ITM stimulus
+//! port, for probes that can capture the SWO output but don't support semihosting, or where
+//! semihosting is too slow. The ITM and the stimulus port must have been enabled, e.g. by the
+//! debugger; the sink is skipped otherwise.
+//!
+//! ``` ignore
+//! #[entry]
+//! fn main() -> ! {
+//!     let itm: &'static mut _ = cortex_m::singleton!(: ItmSink = ItmSink::new(0)).unwrap();
+//!     panic_semihosting::add_sink(itm).ok();
+//!     panic_semihosting::set_semihosting_sink(false);
+//!     // ..
+//! }
+//! ```
+//!
+//! Every word is written once the stimulus port reports that its FIFO is ready. The flag is polled
+//! at most 100,000 times, see `ItmSink::spin_limit`, so a stalled trace output drops the rest of
+//! the report instead of hanging the panic handler. This feature is not available on ARMv6-M and
+//! ARMv8-M Baseline, which have no ITM. `ItmSink::new` checks the port number with `assert!` in a
+//! `const fn`, so this feature requires Rust 1.57 or newer.
+//!
+//! ## `stdout` and `stderr`
+//!
+//! These features select the host stream(s) the panic message is written to. When neither feature
+//! is enabled the message is written to the host stderr. Enabling only `stdout` redirects the
+//! message to the host stdout; enabling both writes the message to both streams, stderr first.
+//!
 //! ## `inline-asm`
 //!
 //! When this feature is enabled semihosting is implemented using inline assembly (`asm!`) and
@@ -56,40 +581,541 @@
 //! When this feature is disabled semihosting is implemented using FFI calls into an external
 //! assembly file and compiling this crate works on stable and beta.
 
+#![cfg_attr(all(feature = "alloc", not(test)), feature(alloc_error_handler))]
 #![deny(missing_docs)]
 #![deny(warnings)]
 #![no_std]
 
 extern crate cortex_m;
+#[cfg(any(feature = "default-handler", feature = "hard-fault"))]
+extern crate cortex_m_rt;
 extern crate cortex_m_semihosting as sh;
+#[cfg(feature = "flash-record")]
+extern crate embedded_storage;
+#[cfg(test)]
+extern crate std;
 
 use core::fmt::Write;
 use core::panic::PanicInfo;
+#[cfg(feature = "exit-extended")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{self, Ordering};
 
-#[cfg(not(feature = "exit"))]
 use cortex_m::asm;
 use cortex_m::interrupt;
-#[cfg(feature = "exit")]
+#[cfg(feature = "detect-debugger")]
+use cortex_m::peripheral::DCB;
+#[cfg(feature = "reset-without-debugger")]
+use cortex_m::peripheral::SCB;
+#[cfg(not(feature = "exit-extended"))]
 use sh::debug::{self, EXIT_FAILURE};
-use sh::hio;
 
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    interrupt::disable();
+pub use action::TerminalAction;
+#[cfg(feature = "alloc")]
+pub use alloc_error::AllocatorStats;
+use buffer::Buffer;
+#[cfg(feature = "flash-record")]
+use embedded_storage::nor_flash::NorFlash;
+#[cfg(feature = "flash-record")]
+pub use flash::{FlashError, FlashRing};
+#[cfg(feature = "itm")]
+pub use itm::ItmSink;
+#[cfg(feature = "panic-record")]
+pub use record::PanicRecord;
+pub use sink::{PanicSink, SinkError, MAX_SINKS};
+#[cfg(feature = "timestamp-user")]
+pub use timestamp::Clock;
+
+mod action;
+#[cfg(feature = "alloc")]
+mod alloc_error;
+#[cfg(any(
+    feature = "backtrace-exidx",
+    feature = "backtrace-fp",
+    feature = "backtrace-scan"
+))]
+mod backtrace;
+mod buffer;
+#[cfg(feature = "build-info")]
+mod build_info;
+#[cfg(feature = "default-handler")]
+mod default_handler;
+#[cfg(feature = "exception-frame")]
+mod exception;
+// NOTE `default-handler` only uses the exception names and `exception-frame` only `VECTACTIVE`
+#[cfg(any(
+    feature = "default-handler",
+    feature = "exception-frame",
+    feature = "fault-registers",
+    feature = "panic-record"
+))]
+#[cfg_attr(
+    not(any(feature = "fault-registers", feature = "panic-record")),
+    allow(dead_code)
+)]
+mod fault;
+#[cfg(feature = "flash-record")]
+mod flash;
+#[cfg(all(feature = "panic-handler", not(test)))]
+mod handler;
+#[cfg(feature = "hard-fault")]
+mod hard_fault;
+#[cfg(feature = "hooks")]
+mod hooks;
+#[cfg(feature = "itm")]
+mod itm;
+#[cfg(feature = "panic-record")]
+mod record;
+#[cfg(feature = "core-registers")]
+mod registers;
+mod sink;
+mod timestamp;
+#[allow(dead_code)]
+mod config {
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
+
+/// Reports the panic `info` to the host
+///
+/// This is what the panic handler provided by this crate uses to log the panic message. It's meant
+/// to be used from a custom panic handler, in which case the `panic-handler` feature should be
+/// disabled.
+///
+/// The report is written with interrupts disabled; they are restored to their previous state
+/// before this function returns.
+pub fn report(info: &PanicInfo) {
+    report_from(info, &Snapshot::capture())
+}
+
+/// State of the processor captured on entry to the panic handler, before interrupts are disabled
+struct Snapshot {
+    #[cfg(feature = "core-registers")]
+    registers: registers::CoreRegisters,
+}
 
-    if let Ok(mut hstdout) = hio::hstdout() {
-        writeln!(hstdout, "{}", info).ok();
+impl Snapshot {
+    /// Captures the state of the processor; always inlined so that it's attributed to the caller
+    #[inline(always)]
+    fn capture() -> Self {
+        Snapshot {
+            #[cfg(feature = "core-registers")]
+            registers: registers::CoreRegisters::read(),
+        }
     }
+}
+
+/// Reports the panic `info` along with the processor state captured in `snapshot`
+// NOTE `snapshot` is empty, and thus unused, when no feature that captures state is enabled
+#[allow(unused_variables)]
+fn report_from(info: &PanicInfo, snapshot: &Snapshot) {
+    interrupt::free(|_| {
+        // if this panic interrupted the writing of a report, finish that first
+        sink::resume();
+
+        #[cfg(feature = "panic-record")]
+        let record = record::capture(info);
+        #[cfg(feature = "panic-record")]
+        record::save(&record);
+
+        // NOTE(unsafe) interrupts are disabled; a buffer taken by a report that panicked midway is
+        // never used again
+        let mut buffer = unsafe { Buffer::take() };
+        timestamp::write(&mut buffer).ok();
+        writeln!(buffer, "{}", info).ok();
+
+        #[cfg(feature = "build-info")]
+        build_info::write(&mut buffer).ok();
+
+        #[cfg(feature = "fault-registers")]
+        write!(buffer, "{}", fault::FaultRegisters::read()).ok();
+
+        #[cfg(feature = "exception-frame")]
+        exception::write(&mut buffer).ok();
+
+        #[cfg(feature = "core-registers")]
+        write!(buffer, "{}", snapshot.registers).ok();
 
+        #[cfg(any(
+            feature = "backtrace-exidx",
+            feature = "backtrace-fp",
+            feature = "backtrace-scan"
+        ))]
+        backtrace::write(&mut buffer).ok();
+
+        sink::write(buffer.as_bytes());
+
+        // NOTE after the report: a flash operation that faults or hangs must not prevent it
+        #[cfg(feature = "flash-record")]
+        flash::store(&record);
+    })
+}
+
+/// Returns `true` if a debugger is attached to the microcontroller
+///
+/// With the `detect-debugger` feature this reads the C_DEBUGEN bit of the Debug Halting Control and
+/// Status Register (DHCSR). Without it a debugger is assumed to be attached and this always returns
+/// `true`.
+pub fn debugger_attached() -> bool {
     match () {
-        // Exit the QEMU process
-        #[cfg(feature = "exit")]
+        #[cfg(feature = "detect-debugger")]
+        () => DCB::is_debugger_attached(),
+        #[cfg(not(feature = "detect-debugger"))]
+        () => true,
+    }
+}
+
+/// Runs the hooks registered with [`panic_hook!`](macro.panic_hook.html)
+///
+/// Hooks run in link order. A hook that panics is not called again; the panic handler resumes with
+/// the next hook. This is what the panic handler provided by this crate calls, after disabling
+/// interrupts and before reporting the panic; custom panic handlers can call it too.
+#[cfg(feature = "hooks")]
+pub fn run_hooks() {
+    hooks::run()
+}
+
+/// Registers a safe-state hook to be run by the panic handler before the panic is reported
+///
+/// The argument must be a path to a `fn()`. Using this macro requires the `hooks` feature and
+/// passing the `panic-semihosting.x` linker script to the linker; see the crate level
+/// documentation. It requires Rust 1.37 or newer.
+///
+/// ``` ignore
+/// #[macro_use]
+/// extern crate panic_semihosting;
+///
+/// fn deenergize_outputs() {
+///     // ..
+/// }
+///
+/// panic_hook!(deenergize_outputs);
+/// ```
+#[cfg(feature = "hooks")]
+#[macro_export]
+macro_rules! panic_hook {
+    ($hook:path) => {
+        const _: () = {
+            #[link_section = ".panic_semihosting_hooks"]
+            #[used]
+            static HOOK: fn() = $hook;
+        };
+    };
+}
+
+/// Embeds the build metadata of the application in the firmware
+///
+/// The metadata, `key=value` lines with the package name, version and profile (`debug` or
+/// `release`) of the crate that invokes the macro, is placed in the `.panic_semihosting_build_info`
+/// linker section, where the panic handler, with the `build-info` feature, and host tools can find
+/// it. A git hash, or any other revision identifier, can be included as well; the argument must
+/// expand to a string literal. Invoke this macro once, from the binary crate.
+///
+/// ``` ignore
+/// #[macro_use]
+/// extern crate panic_semihosting;
+///
+/// // set by the build script with `println!("cargo:rustc-env=GIT_HASH={}", hash)`
+/// build_info!(git_hash = env!("GIT_HASH"));
+/// ```
+#[cfg(feature = "build-info")]
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build_info!(@record "");
+    };
+    (git_hash = $($hash:tt)+) => {
+        $crate::build_info!(@record concat!("git=", $($hash)+, "\n"));
+    };
+    (@record $($git:tt)+) => {
+        const _: () = {
+            #[cfg(debug_assertions)]
+            const INFO: &str = $crate::build_info!(@info "debug", $($git)+);
+            #[cfg(not(debug_assertions))]
+            const INFO: &str = $crate::build_info!(@info "release", $($git)+);
+
+            #[link_section = ".panic_semihosting_build_info"]
+            #[used]
+            static BUILD_INFO: [u8; INFO.len()] = $crate::__build_info(INFO);
+        };
+    };
+    (@info $profile:tt, $($git:tt)+) => {
+        concat!(
+            "name=",
+            env!("CARGO_PKG_NAME"),
+            "\nversion=",
+            env!("CARGO_PKG_VERSION"),
+            "\n",
+            $($git)+,
+            "profile=",
+            $profile,
+            "\n"
+        )
+    };
+}
+
+#[cfg(feature = "build-info")]
+#[doc(hidden)]
+pub const fn __build_info<const N: usize>(info: &str) -> [u8; N] {
+    let bytes = info.as_bytes();
+    let mut array = [0; N];
+    let mut i = 0;
+    while i < N {
+        array[i] = bytes[i];
+        i += 1;
+    }
+    array
+}
+
+/// `SYS_EXIT_EXTENDED` semihosting operation number
+#[cfg(feature = "exit-extended")]
+const SYS_EXIT_EXTENDED: usize = 0x20;
+
+/// `ADP_Stopped_ApplicationExit` reason code
+#[cfg(feature = "exit-extended")]
+const ADP_STOPPED_APPLICATION_EXIT: usize = 0x20026;
+
+/// Failure that ends the program; each one reports its own exit code with `exit-extended`
+#[cfg(feature = "exit-extended")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitReason {
+    /// A panic; exit code 1 by default, see `PANIC_SEMIHOSTING_EXIT_CODE`
+    Panic,
+    /// An allocation error reported by the `alloc` feature's handler; exit code 2 by default
+    AllocError,
+    /// A hard fault reported by the `hard-fault` feature's handler; exit code 3 by default
+    HardFault,
+    /// An exception or interrupt reported by the `default-handler` feature's handler; exit code 4
+    /// by default
+    UnhandledException,
+}
+
+/// Exit codes reported to the host by `terminate`, indexed by `ExitReason`
+#[cfg(feature = "exit-extended")]
+static EXIT_CODES: [AtomicUsize; 4] = [
+    AtomicUsize::new(config::EXIT_CODE as usize),
+    AtomicUsize::new(2),
+    AtomicUsize::new(3),
+    AtomicUsize::new(4),
+];
+
+/// Failure being reported; selects the exit code
+#[cfg(feature = "exit-extended")]
+static EXIT_REASON: AtomicUsize = AtomicUsize::new(ExitReason::Panic as usize);
+
+/// Sets the exit code that the panic handler reports to the host
+///
+/// The code applies to every panic from this point on. Use it to let the host tell apart categories
+/// of failures, e.g. call `set_exit_code(3)` right before panicking on a timeout, or from a panic
+/// hook. This is the same as `set_exit_code_for(ExitReason::Panic, code)`.
+#[cfg(feature = "exit-extended")]
+pub fn set_exit_code(code: u32) {
+    set_exit_code_for(ExitReason::Panic, code)
+}
+
+/// Sets the exit code reported to the host when the program ends because of `reason`
+#[cfg(feature = "exit-extended")]
+pub fn set_exit_code_for(reason: ExitReason, code: u32) {
+    EXIT_CODES[reason as usize].store(code as usize, Ordering::Relaxed);
+}
+
+/// Performs the terminal action, reporting the exit code of `reason` if it's an exit call
+#[cfg(all(
+    feature = "exit-extended",
+    any(feature = "alloc", feature = "default-handler", feature = "hard-fault")
+))]
+#[cfg_attr(not(target_arch = "arm"), allow(dead_code))]
+fn terminate_with(reason: ExitReason) -> ! {
+    EXIT_REASON.store(reason as usize, Ordering::Relaxed);
+
+    terminate()
+}
+
+/// Performs an exit semihosting call that reports `code` to the host
+#[cfg(feature = "exit-extended")]
+fn exit_extended(code: usize) {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, code];
+    unsafe {
+        sh::syscall(SYS_EXIT_EXTENDED, &block);
+    }
+}
+
+/// Registers a function that returns the usage statistics of the heap
+///
+/// The allocation error handler calls it and appends the statistics to its report. `hook` runs
+/// with interrupts disabled, after an allocation failed, so it must not allocate.
+#[cfg(feature = "alloc")]
+pub fn set_allocator_stats_hook(hook: fn() -> AllocatorStats) {
+    alloc_error::set_stats_hook(hook)
+}
+
+/// Registers the clock that timestamps the reports
+///
+/// Until a clock is registered the reports carry no user timestamp.
+#[cfg(feature = "timestamp-user")]
+pub fn set_clock(clock: &'static dyn Clock) {
+    timestamp::set_clock(clock)
+}
+
+/// Returns the panic record stored by the last panic, if there's one
+///
+/// The record survives resets that don't power cycle the RAM; after a power-on reset, or once
+/// [`clear_panic_record`](fn.clear_panic_record.html) is called, there's none.
+#[cfg(feature = "panic-record")]
+pub fn panic_record() -> Option<PanicRecord> {
+    record::load()
+}
+
+/// Clears the panic record stored by the last panic
+#[cfg(feature = "panic-record")]
+pub fn clear_panic_record() {
+    record::clear()
+}
+
+/// Registers the flash ring the panic handler stores a record of the panic in
+///
+/// Until a ring is registered no record is stored in flash.
+#[cfg(feature = "flash-record")]
+pub fn set_flash_ring<F>(ring: &'static mut FlashRing<F>)
+where
+    F: NorFlash + Send + 'static,
+{
+    flash::set_ring(ring)
+}
+
+/// Reports the panic stored by the previous boot, if any, to the host and then clears it
+///
+/// The report has the same format as the one of a live panic, under a `previous boot:` header.
+/// Nothing is reported, and the record is kept, if no sink is available, e.g. because no debugger
+/// is attached (see [`debugger_attached`](fn.debugger_attached.html)) and no other sink was added.
+/// Returns `true` if a panic was reported.
+///
+/// Call this early, e.g. at the beginning of `main`, before anything has a chance to panic and
+/// replace the record. Don't call it from a `#[pre_init]` function: it uses statics, like the
+/// registered sinks and the report buffer, that are only initialized after `pre_init` returns, so
+/// reading them there is undefined behavior.
+#[cfg(feature = "panic-record")]
+pub fn report_previous_boot() -> bool {
+    let record = match record::load() {
+        Some(record) => record,
+        None => return false,
+    };
+
+    if !sink::available() {
+        return false;
+    }
+
+    interrupt::free(|_| {
+        // NOTE(unsafe) interrupts are disabled; a panic can only abandon this buffer
+        let mut buffer = unsafe { Buffer::take() };
+        write!(buffer, "previous boot:\n{}", record).ok();
+
+        sink::write(buffer.as_bytes());
+    });
+
+    record::clear();
+    true
+}
+
+/// Adds `sink` to the sinks every report is written to; returns it back if there are already
+/// [`MAX_SINKS`](constant.MAX_SINKS.html) sinks
+///
+/// The reports are written to the sinks in the order they were added, after the semihosting sink.
+pub fn add_sink(sink: &'static mut dyn PanicSink) -> Result<(), &'static mut dyn PanicSink> {
+    sink::add(sink)
+}
+
+/// Enables or disables the semihosting sink, which is enabled by default
+///
+/// Disable it when the reports only go to other sinks and semihosting is not supported by the
+/// probe, or too slow. Disabling it stops every other semihosting call too: the `SYS_CLOCK` and
+/// `SYS_TIME` timestamps are left out, the command line is not read and the `Exit` terminal action
+/// triggers a breakpoint instead.
+pub fn set_semihosting_sink(enabled: bool) {
+    sink::set_semihosting(enabled)
+}
+
+/// Selects, at runtime, the terminal action performed by `terminate`
+///
+/// This overrides the action selected at compile time with the `exit` feature, as well as the one
+/// passed on the command line with the `cmdline` feature. The selection is kept across resets that
+/// don't power cycle the RAM.
+pub fn set_terminal_action(action: TerminalAction) {
+    action::set(action)
+}
+
+/// Returns the terminal action that `terminate` will perform
+///
+/// With the `cmdline` feature the first call made with a debugger attached, and the semihosting
+/// sink enabled, reads the command line with a semihosting call and remembers the result; calls
+/// made without a debugger leave the command line out and make no semihosting call.
+pub fn terminal_action() -> TerminalAction {
+    action::get()
+}
+
+/// Performs the terminal action of the panic handler and never returns
+///
+/// The terminal action is an exit semihosting call or a breakpoint; see
+/// [`terminal_action`](fn.terminal_action.html). With the `exit-extended` feature the exit call
+/// reports the exit code of a panic, see [`set_exit_code`](fn.set_exit_code.html), unless this is
+/// called by one of the other handlers provided by this crate. In either case this function then
+/// goes into an infinite loop.
+///
+/// If no debugger is attached (see [`debugger_attached`](fn.debugger_attached.html)) neither the
+/// semihosting call nor the breakpoint is issued; instead this resets the system, with the
+/// `reset-without-debugger` feature, or silently goes into the infinite loop.
+pub fn terminate() -> ! {
+    if debugger_attached() {
+        match terminal_action() {
+            // Exit the QEMU process; unless semihosting has been disabled, see
+            // `set_semihosting_sink`
+            TerminalAction::Exit if sink::semihosting_enabled() => exit(),
+            // OK to fire a breakpoint here because we know the microcontroller is connected to a
+            // debugger
+            _ => asm::bkpt(),
+        }
+    } else {
+        #[cfg(feature = "reset-without-debugger")]
+        SCB::sys_reset();
+    }
+
+    loop {
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// Performs an exit semihosting call that reports a failure to the host
+fn exit() {
+    match () {
+        #[cfg(not(feature = "exit-extended"))]
         () => debug::exit(EXIT_FAILURE),
-        // OK to fire a breakpoint here because we know the microcontroller is connected to a
-        // debugger
-        #[cfg(not(feature = "exit"))]
-        () => asm::bkpt(),
+        // Report the selected exit code
+        #[cfg(feature = "exit-extended")]
+        () => {
+            let reason = EXIT_REASON.load(Ordering::Relaxed);
+            exit_extended(EXIT_CODES[reason].load(Ordering::Relaxed))
+        }
     }
+}
+
+#[cfg(any(
+    feature = "backtrace-exidx",
+    feature = "backtrace-fp",
+    feature = "backtrace-scan",
+    feature = "exception-frame"
+))]
+extern "C" {
+    // Provided by cortex-m-rt's `link.x`
+    static _stack_start: u32;
+}
 
-    loop {}
+/// Returns the top (highest address) of the main stack
+#[cfg(any(
+    feature = "backtrace-exidx",
+    feature = "backtrace-fp",
+    feature = "backtrace-scan",
+    feature = "exception-frame"
+))]
+fn stack_top() -> u32 {
+    // NOTE(unsafe) only the address of the symbol is used
+    unsafe { &_stack_start as *const u32 as u32 }
 }
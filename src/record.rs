@@ -0,0 +1,442 @@
+//! Panic record that survives resets
+//!
+//! The record lives in the `.uninit` section that `cortex-m-rt`'s linker script reserves in RAM;
+//! the runtime doesn't initialize that section on boot, so its contents survive a reset that
+//! doesn't power cycle the RAM. A magic number and a CRC tell a valid record apart from the
+//! contents of RAM after a power-on reset.
+
+use core::mem::{self, MaybeUninit};
+use core::panic::PanicInfo;
+use core::{fmt, ptr, slice, str};
+
+use fault::FaultRegisters;
+
+/// Marks a stored record; change it when the layout of `Record` changes
+const MAGIC: u32 = 0x5053_5201;
+
+/// Maximum length, in bytes, of the stored message
+const MESSAGE_SIZE: usize = 128;
+/// Maximum length, in bytes, of the stored file name
+const FILE_SIZE: usize = 64;
+
+/// `Record.truncated`: the message didn't fit
+const MESSAGE_TRUNCATED: u8 = 1 << 0;
+/// `Record.truncated`: the file name didn't fit; its beginning was dropped
+const FILE_TRUNCATED: u8 = 1 << 1;
+
+#[link_section = ".uninit.panic_semihosting_record"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+/// Layout of the record in RAM
+// NOTE all the fields are words or arrays of bytes whose size is a multiple of 4 so that the
+// record has no padding, which the CRC would cover
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Record {
+    magic: u32,
+    line: u32,
+    column: u32,
+    message_len: u8,
+    file_len: u8,
+    truncated: u8,
+    reserved: u8,
+    message: [u8; MESSAGE_SIZE],
+    file: [u8; FILE_SIZE],
+    fault: FaultRegisters,
+    /// CRC-32 of all the fields above
+    crc: u32,
+}
+
+/// Panic stored by a previous boot; see [`panic_record`](fn.panic_record.html)
+#[derive(Clone, Copy)]
+pub struct PanicRecord {
+    record: Record,
+}
+
+impl PanicRecord {
+    /// Creates a record out of the given location, with all the fault registers cleared
+    #[cfg(test)]
+    pub fn new(message: &str, file: &str, line: u32, column: u32) -> Self {
+        let mut record = Record {
+            magic: MAGIC,
+            line,
+            column,
+            message_len: message.len() as u8,
+            file_len: file.len() as u8,
+            truncated: 0,
+            reserved: 0,
+            message: [0; MESSAGE_SIZE],
+            file: [0; FILE_SIZE],
+            fault: FaultRegisters {
+                icsr: 0,
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                cfsr: 0,
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                hfsr: 0,
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                mmfar: 0,
+                #[cfg(not(any(armv6m, armv8m_base)))]
+                bfar: 0,
+            },
+            crc: 0,
+        };
+        record.message[..message.len()].copy_from_slice(message.as_bytes());
+        record.file[..file.len()].copy_from_slice(file.as_bytes());
+        record.crc = crc(&record);
+
+        PanicRecord { record }
+    }
+
+    /// Returns the panic message, possibly truncated
+    pub fn message(&self) -> &str {
+        utf8(&self.record.message[..usize::from(self.record.message_len)])
+    }
+
+    /// Returns `true` if the message was truncated
+    pub fn message_truncated(&self) -> bool {
+        self.record.truncated & MESSAGE_TRUNCATED != 0
+    }
+
+    /// Returns the name of the source file of the panic; its beginning is dropped if it's too long
+    pub fn file(&self) -> &str {
+        utf8(&self.record.file[..usize::from(self.record.file_len)])
+    }
+
+    /// Returns the line of the panic
+    pub fn line(&self) -> u32 {
+        self.record.line
+    }
+
+    /// Returns the column of the panic
+    pub fn column(&self) -> u32 {
+        self.record.column
+    }
+
+    /// Returns the value of the Interrupt Control and State Register (ICSR) at the time of the
+    /// panic
+    pub fn icsr(&self) -> u32 {
+        self.record.fault.icsr
+    }
+
+    /// Returns the value of the Configurable Fault Status Register (CFSR) at the time of the panic
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub fn cfsr(&self) -> u32 {
+        self.record.fault.cfsr
+    }
+
+    /// Returns the value of the HardFault Status Register (HFSR) at the time of the panic
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub fn hfsr(&self) -> u32 {
+        self.record.fault.hfsr
+    }
+
+    /// Returns the value of the MemManage Fault Address Register (MMFAR) at the time of the panic
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub fn mmfar(&self) -> u32 {
+        self.record.fault.mmfar
+    }
+
+    /// Returns the value of the BusFault Address Register (BFAR) at the time of the panic
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    pub fn bfar(&self) -> u32 {
+        self.record.fault.bfar
+    }
+}
+
+impl fmt::Display for PanicRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "panicked at {}{}:{}:{}:",
+            if self.record.truncated & FILE_TRUNCATED != 0 {
+                "..."
+            } else {
+                ""
+            },
+            self.file(),
+            self.line(),
+            self.column()
+        )?;
+        f.write_str(self.message())?;
+        if self.message_truncated() {
+            f.write_str("... (truncated)")?;
+        }
+        writeln!(f)?;
+
+        write!(f, "{}", self.record.fault)
+    }
+}
+
+/// Size, in bytes, of an encoded record
+pub const SIZE: usize = mem::size_of::<Record>();
+
+/// Records the panic `info` along with the current fault registers
+pub fn capture(info: &PanicInfo) -> PanicRecord {
+    let mut record = Record {
+        magic: MAGIC,
+        line: 0,
+        column: 0,
+        message_len: 0,
+        file_len: 0,
+        truncated: 0,
+        reserved: 0,
+        message: [0; MESSAGE_SIZE],
+        file: [0; FILE_SIZE],
+        fault: FaultRegisters::read(),
+        crc: 0,
+    };
+
+    let mut message = Truncate {
+        bytes: &mut record.message,
+        len: 0,
+        truncated: false,
+    };
+    fmt::write(&mut message, format_args!("{}", info.message())).ok();
+    let (len, truncated) = (message.len, message.truncated);
+    record.message_len = len as u8;
+    if truncated {
+        record.truncated |= MESSAGE_TRUNCATED;
+    }
+
+    if let Some(location) = info.location() {
+        // keep the end of the path, which is the most specific part
+        let file = location.file();
+        let mut start = file.len().saturating_sub(FILE_SIZE);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        if start != 0 {
+            record.truncated |= FILE_TRUNCATED;
+        }
+
+        let file = &file.as_bytes()[start..];
+        record.file[..file.len()].copy_from_slice(file);
+        record.file_len = file.len() as u8;
+        record.line = location.line();
+        record.column = location.column();
+    }
+
+    record.crc = crc(&record);
+
+    PanicRecord { record }
+}
+
+/// Stores `record` in RAM, replacing any previous record
+pub fn save(record: &PanicRecord) {
+    // NOTE(unsafe) only called from the panic handler, with interrupts disabled
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(RECORD) as *mut Record, record.record) }
+}
+
+/// Returns the record stored in RAM, if there's a valid one
+pub fn load() -> Option<PanicRecord> {
+    // NOTE(unsafe) any bit pattern is a valid `Record`; RAM that was never written holds garbage,
+    // which the magic number and the CRC rule out
+    validate(unsafe { ptr::read_volatile(ptr::addr_of!(RECORD) as *const Record) })
+}
+
+/// Encodes `record` into `bytes`, which must be `SIZE` bytes long
+#[cfg(feature = "flash-record")]
+pub fn encode(record: &PanicRecord, bytes: &mut [u8]) {
+    // NOTE(unsafe) `Record` has no padding
+    bytes.copy_from_slice(unsafe { as_bytes(&record.record, SIZE) });
+}
+
+/// Decodes the record in `bytes`, which must be `SIZE` bytes long; returns `None` if it's not valid
+#[cfg(feature = "flash-record")]
+pub fn decode(bytes: &[u8]) -> Option<PanicRecord> {
+    assert_eq!(bytes.len(), SIZE);
+
+    // NOTE(unsafe) any bit pattern is a valid `Record`
+    validate(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Record) })
+}
+
+/// Checks the magic number, the lengths and the CRC of `record`
+fn validate(record: Record) -> Option<PanicRecord> {
+    if record.magic != MAGIC
+        || usize::from(record.message_len) > MESSAGE_SIZE
+        || usize::from(record.file_len) > FILE_SIZE
+        || record.crc != crc(&record)
+    {
+        return None;
+    }
+
+    Some(PanicRecord { record })
+}
+
+/// Invalidates the stored record
+pub fn clear() {
+    // NOTE(unsafe) a single word write; a concurrent `save` can only happen from the panic handler,
+    // which never returns
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(RECORD) as *mut u32, 0) }
+}
+
+/// CRC of all the fields of `record` but `crc`
+fn crc(record: &Record) -> u32 {
+    // NOTE(unsafe) `Record` has no padding
+    crc32(unsafe { as_bytes(record, SIZE - mem::size_of::<u32>()) })
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Returns the first `len` bytes of `record`
+///
+/// # Safety
+///
+/// `len` must not exceed the size of `Record`
+unsafe fn as_bytes(record: &Record, len: usize) -> &[u8] {
+    slice::from_raw_parts(record as *const Record as *const u8, len)
+}
+
+/// Returns the longest valid UTF-8 prefix of `bytes`
+fn utf8(bytes: &[u8]) -> &str {
+    match str::from_utf8(bytes) {
+        Ok(s) => s,
+        // NOTE(unsafe) `valid_up_to` bytes are valid UTF-8
+        Err(e) => unsafe { str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+    }
+}
+
+/// Formats into a byte array, silently dropping what doesn't fit
+struct Truncate<'a> {
+    bytes: &'a mut [u8],
+    len: usize,
+    truncated: bool,
+}
+
+impl<'a> fmt::Write for Truncate<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = self.bytes.len() - self.len;
+        let mut end = s.len().min(free);
+        // don't split a multi-byte character
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        if end < s.len() {
+            self.truncated = true;
+            return Err(fmt::Error);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::{crc, crc32, validate, PanicRecord, Truncate, FILE_SIZE, MAGIC, MESSAGE_SIZE};
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn valid() {
+        let record = PanicRecord::new("oops", "src/main.rs", 20, 5).record;
+        let record = validate(record).unwrap();
+
+        assert_eq!(record.message(), "oops");
+        assert!(!record.message_truncated());
+        assert_eq!(record.file(), "src/main.rs");
+        assert_eq!(record.line(), 20);
+        assert_eq!(record.column(), 5);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut record = PanicRecord::new("oops", "src/main.rs", 20, 5).record;
+        record.magic = !MAGIC;
+        record.crc = crc(&record);
+
+        assert!(validate(record).is_none());
+    }
+
+    #[test]
+    fn bad_lengths() {
+        let mut record = PanicRecord::new("oops", "src/main.rs", 20, 5).record;
+        record.message_len = MESSAGE_SIZE as u8 + 1;
+        record.crc = crc(&record);
+        assert!(validate(record).is_none());
+
+        let mut record = PanicRecord::new("oops", "src/main.rs", 20, 5).record;
+        record.file_len = FILE_SIZE as u8 + 1;
+        record.crc = crc(&record);
+        assert!(validate(record).is_none());
+    }
+
+    #[test]
+    fn bad_crc() {
+        let mut record = PanicRecord::new("oops", "src/main.rs", 20, 5).record;
+        record.line += 1;
+        assert!(validate(record).is_none());
+
+        let mut record = PanicRecord::new("oops", "src/main.rs", 20, 5).record;
+        record.crc ^= 1;
+        assert!(validate(record).is_none());
+    }
+
+    #[test]
+    fn truncate_fits() {
+        let mut bytes = [0; 8];
+        let mut truncate = Truncate {
+            bytes: &mut bytes,
+            len: 0,
+            truncated: false,
+        };
+
+        assert!(write!(truncate, "oops{}", 1234).is_ok());
+        assert_eq!(truncate.len, 8);
+        assert!(!truncate.truncated);
+        assert_eq!(&bytes, b"oops1234");
+    }
+
+    #[test]
+    fn truncate_overflow() {
+        let mut bytes = [0; 4];
+        let mut truncate = Truncate {
+            bytes: &mut bytes,
+            len: 0,
+            truncated: false,
+        };
+
+        assert!(truncate.write_str("oops!").is_err());
+        assert_eq!(truncate.len, 4);
+        assert!(truncate.truncated);
+        assert_eq!(&bytes, b"oops");
+    }
+
+    #[test]
+    fn truncate_char_boundary() {
+        let mut bytes = [0; 4];
+        let mut truncate = Truncate {
+            bytes: &mut bytes,
+            len: 0,
+            truncated: false,
+        };
+
+        // `é` takes 2 bytes and doesn't fit after `abc`
+        assert!(truncate.write_str("abcé").is_err());
+        assert_eq!(truncate.len, 3);
+        assert!(truncate.truncated);
+        assert_eq!(&bytes[..3], b"abc");
+    }
+}
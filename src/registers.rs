@@ -0,0 +1,96 @@
+//! Core register snapshot
+
+#[cfg(target_arch = "arm")]
+use core::arch::asm;
+use core::fmt;
+
+#[cfg(not(any(armv6m, armv8m_base)))]
+use cortex_m::register::{basepri, faultmask};
+use cortex_m::register::{control, msp, primask, psp};
+
+/// Snapshot of the stack pointers and the special purpose registers
+#[derive(Clone, Copy)]
+pub struct CoreRegisters {
+    msp: u32,
+    psp: u32,
+    control: control::Control,
+    primask: primask::Primask,
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    basepri: u8,
+    #[cfg(not(any(armv6m, armv8m_base)))]
+    faultmask: faultmask::Faultmask,
+    lr: u32,
+}
+
+impl CoreRegisters {
+    /// Reads the registers
+    ///
+    /// This is always inlined so that `LR` holds the return address of the function that calls it.
+    #[inline(always)]
+    pub fn read() -> Self {
+        CoreRegisters {
+            lr: lr(),
+            msp: msp::read(),
+            psp: psp::read(),
+            control: control::read(),
+            primask: primask::read(),
+            #[cfg(not(any(armv6m, armv8m_base)))]
+            basepri: basepri::read(),
+            #[cfg(not(any(armv6m, armv8m_base)))]
+            faultmask: faultmask::read(),
+        }
+    }
+}
+
+impl fmt::Display for CoreRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "core registers:")?;
+        writeln!(f, "  MSP       = {:#010x}", self.msp)?;
+        writeln!(f, "  PSP       = {:#010x}", self.psp)?;
+        writeln!(
+            f,
+            "  CONTROL   = {:#010x} ({}, {})",
+            self.control.bits(),
+            if self.control.npriv().is_privileged() {
+                "privileged"
+            } else {
+                "unprivileged"
+            },
+            if self.control.spsel().is_msp() {
+                "MSP"
+            } else {
+                "PSP"
+            }
+        )?;
+        writeln!(
+            f,
+            "  PRIMASK   = {}",
+            if self.primask.is_active() { 0 } else { 1 }
+        )?;
+        #[cfg(not(any(armv6m, armv8m_base)))]
+        {
+            writeln!(f, "  BASEPRI   = {:#04x}", self.basepri)?;
+            writeln!(
+                f,
+                "  FAULTMASK = {}",
+                if self.faultmask.is_active() { 0 } else { 1 }
+            )?;
+        }
+        writeln!(f, "  LR        = {:#010x}", self.lr)
+    }
+}
+
+/// Reads the link register
+#[inline(always)]
+fn lr() -> u32 {
+    match () {
+        #[cfg(target_arch = "arm")]
+        () => {
+            let lr: u32;
+            unsafe { asm!("mov {}, lr", out(reg) lr, options(nomem, nostack, preserves_flags)) };
+            lr
+        }
+        #[cfg(not(target_arch = "arm"))]
+        () => 0,
+    }
+}
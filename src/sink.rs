@@ -0,0 +1,230 @@
+//! Output backends the reports are written to
+//!
+//! A report is written to the semihosting sink, unless it has been disabled, and then to every
+//! registered sink, in the order they were added. A sink that is not available or whose write
+//! fails is skipped without affecting the others. A sink that panics, or faults, midway is
+//! disabled for good: the handler of that panic, or fault, calls `resume` to write the interrupted
+//! report to the sinks that come after it, and then writes its own report to the other sinks only.
+
+use core::cell::UnsafeCell;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use cortex_m::interrupt;
+use sh::hio;
+
+/// Maximum number of sinks that can be registered with `add`
+pub const MAX_SINKS: usize = 4;
+
+/// Index of the semihosting sink in `ACTIVE` and `DISABLED`; the registered sinks follow it
+const SEMIHOSTING: usize = 0;
+
+/// `ACTIVE` value when no sink is being written to
+const NONE: usize = !0;
+
+/// Destination of the panic reports; see [`add_sink`](fn.add_sink.html)
+///
+/// All the methods are called with interrupts disabled, from the panic handler or another fault
+/// handler; they must not rely on interrupts.
+pub trait PanicSink: Send {
+    /// Returns `true` if the sink can be written to; the sink is skipped otherwise
+    fn available(&self) -> bool {
+        true
+    }
+
+    /// Writes all of `bytes`
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SinkError>;
+
+    /// Waits until everything written so far has left the microcontroller
+    fn flush(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// Error returned by a [`PanicSink`](trait.PanicSink.html) that failed to write a report
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SinkError;
+
+/// Writes to the selected host stream(s) using semihosting
+struct Semihosting;
+
+impl PanicSink for Semihosting {
+    /// A semihosting call without a debugger would fault or lock up the core
+    fn available(&self) -> bool {
+        ::debugger_attached()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SinkError> {
+        let mut result = Ok(());
+
+        #[cfg(any(feature = "stderr", not(feature = "stdout")))]
+        {
+            result = result.and(
+                hio::hstderr()
+                    .and_then(|mut hstderr| hstderr.write_all(bytes))
+                    .map_err(|_| SinkError),
+            );
+        }
+
+        #[cfg(feature = "stdout")]
+        {
+            result = result.and(
+                hio::hstdout()
+                    .and_then(|mut hstdout| hstdout.write_all(bytes))
+                    .map_err(|_| SinkError),
+            );
+        }
+
+        result
+    }
+}
+
+struct Sinks(UnsafeCell<[Option<&'static mut dyn PanicSink>; MAX_SINKS]>);
+
+// NOTE(unsafe) only accessed with interrupts disabled; see `write`
+unsafe impl Sync for Sinks {}
+
+// NOTE spelled out because `[None; MAX_SINKS]` requires `Copy` elements; keep it in sync with
+// `MAX_SINKS`
+static SINKS: Sinks = Sinks(UnsafeCell::new([None, None, None, None]));
+
+/// Cleared by `set_semihosting`
+static SEMIHOSTING_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Index of the sink that's being written to
+static ACTIVE: AtomicUsize = AtomicUsize::new(NONE);
+
+/// Bitmask of the sinks that panicked or faulted while being written to
+static DISABLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Address and length of the report `write` is writing; the length is 0 when it's not running
+static REPORT: AtomicUsize = AtomicUsize::new(0);
+static REPORT_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers `sink`; returns it back if all the slots are taken
+pub fn add(sink: &'static mut dyn PanicSink) -> Result<(), &'static mut dyn PanicSink> {
+    interrupt::free(|_| {
+        // NOTE(unsafe) interrupts are disabled and `write` doesn't return while it's running, so
+        // there are no other references to the sinks
+        let sinks = unsafe { &mut *SINKS.0.get() };
+        match sinks.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(sink);
+                Ok(())
+            }
+            None => Err(sink),
+        }
+    })
+}
+
+pub fn set_semihosting(enabled: bool) {
+    SEMIHOSTING_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns `false` if the semihosting sink has been disabled, in which case no other semihosting
+/// call must be made either
+pub fn semihosting_enabled() -> bool {
+    SEMIHOSTING_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if any of the enabled sinks is available
+#[cfg(feature = "panic-record")]
+pub fn available() -> bool {
+    interrupt::free(|_| {
+        let mut available = false;
+        for_each(SEMIHOSTING, |sink| {
+            available |= sink.available();
+            Ok(())
+        });
+        available
+    })
+}
+
+/// Writes `bytes`, followed by a flush, to all the available sinks
+pub fn write(bytes: &[u8]) {
+    interrupt::free(|_| {
+        REPORT.store(bytes.as_ptr() as usize, Ordering::Relaxed);
+        REPORT_LEN.store(bytes.len(), Ordering::Relaxed);
+
+        for_each(SEMIHOSTING, |sink| write_to(sink, bytes));
+
+        REPORT_LEN.store(0, Ordering::Relaxed);
+    })
+}
+
+/// Writes the report that was interrupted by a panic or a fault, if any, to the sinks after the
+/// one that was being written to
+///
+/// Must be called before the report buffer is taken again, as that's where the interrupted report
+/// lives.
+pub fn resume() {
+    interrupt::free(|_| {
+        let active = ACTIVE.load(Ordering::Relaxed);
+        if active == NONE {
+            return;
+        }
+
+        let bytes = match REPORT_LEN.load(Ordering::Relaxed) {
+            // not interrupted while writing a report, e.g. in `PanicSink::available`
+            0 => &[][..],
+            // NOTE(unsafe) the report is in the report buffer, which hasn't been taken since; see
+            // above
+            len => unsafe {
+                slice::from_raw_parts(REPORT.load(Ordering::Relaxed) as *const u8, len)
+            },
+        };
+
+        for_each(active + 1, |sink| write_to(sink, bytes));
+
+        REPORT_LEN.store(0, Ordering::Relaxed);
+    })
+}
+
+/// Writes `bytes`, if any, followed by a flush to `sink`, if it's available
+fn write_to(sink: &mut dyn PanicSink, bytes: &[u8]) -> Result<(), SinkError> {
+    if !bytes.is_empty() && sink.available() {
+        sink.write(bytes)?;
+        sink.flush()?;
+    }
+    Ok(())
+}
+
+/// Calls `f` on every enabled sink, in order, starting with the one at index `first`; must be
+/// called with interrupts disabled
+fn for_each<F>(first: usize, mut f: F)
+where
+    F: FnMut(&mut dyn PanicSink) -> Result<(), SinkError>,
+{
+    // a sink was interrupted by a panic or a fault, which ended up here; it won't be resumed
+    let active = ACTIVE.load(Ordering::Relaxed);
+    if active != NONE {
+        // NOTE a load followed by a store is enough because interrupts are disabled; ARMv6-M has
+        // no atomic read-modify-write operations anyway
+        let disabled = DISABLED.load(Ordering::Relaxed);
+        DISABLED.store(disabled | 1 << active, Ordering::Relaxed);
+    }
+
+    let disabled = DISABLED.load(Ordering::Relaxed);
+    let mut call = |index: usize, sink: &mut dyn PanicSink| {
+        if index >= first && disabled & 1 << index == 0 {
+            ACTIVE.store(index, Ordering::Relaxed);
+            // a failure only affects this sink
+            f(sink).ok();
+        }
+    };
+
+    if semihosting_enabled() {
+        call(SEMIHOSTING, &mut Semihosting);
+    }
+
+    // NOTE(unsafe) interrupts are disabled; a reference taken by an interrupted call, see above,
+    // is abandoned
+    let sinks = unsafe { &mut *SINKS.0.get() };
+    for (index, slot) in sinks.iter_mut().enumerate() {
+        if let Some(sink) = slot {
+            call(SEMIHOSTING + 1 + index, &mut **sink);
+        }
+    }
+
+    ACTIVE.store(NONE, Ordering::Relaxed);
+}
@@ -0,0 +1,84 @@
+//! Timestamps that prefix the reports
+//!
+//! Each enabled source writes its own bracketed timestamp, e.g. `[12.34s] [51234567 cycles] `.
+
+#[cfg(feature = "timestamp-user")]
+use core::cell::Cell;
+use core::fmt;
+#[cfg(feature = "timestamp-user")]
+use core::time::Duration;
+
+#[cfg(feature = "timestamp-user")]
+use cortex_m::interrupt::{self, Mutex};
+#[cfg(feature = "timestamp-dwt")]
+use cortex_m::peripheral::DWT;
+#[cfg(any(feature = "timestamp-sys-clock", feature = "timestamp-sys-time"))]
+use sh::nr;
+
+#[cfg(all(feature = "timestamp-dwt", any(armv6m, armv8m_base)))]
+compile_error!(
+    "the `timestamp-dwt` feature is not available on ARMv6-M and ARMv8-M Baseline: they have no \
+     cycle counter"
+);
+
+/// Source of the timestamp that prefixes the reports; see [`set_clock`](fn.set_clock.html)
+#[cfg(feature = "timestamp-user")]
+pub trait Clock: Sync {
+    /// Returns the time elapsed since an arbitrary point, e.g. the reset of the microcontroller
+    ///
+    /// This is called with interrupts disabled, from the panic handler; it must not panic.
+    fn now(&self) -> Duration;
+}
+
+/// The clock registered with `set_clock`
+#[cfg(feature = "timestamp-user")]
+static CLOCK: Mutex<Cell<Option<&'static dyn Clock>>> = Mutex::new(Cell::new(None));
+
+#[cfg(feature = "timestamp-user")]
+pub fn set_clock(clock: &'static dyn Clock) {
+    interrupt::free(|cs| CLOCK.borrow(cs).set(Some(clock)));
+}
+
+/// Writes the timestamps of all the enabled sources
+///
+/// The semihosting sources are skipped when no debugger is attached or the semihosting sink is
+/// disabled.
+#[allow(unused_variables)]
+pub fn write(f: &mut dyn fmt::Write) -> fmt::Result {
+    #[cfg(feature = "timestamp-sys-clock")]
+    {
+        if ::debugger_attached() && ::sink::semihosting_enabled() {
+            // NOTE(unsafe) SYS_CLOCK takes no parameters; it returns -1 on failure
+            let centiseconds = unsafe { sh::syscall1(nr::CLOCK, 0) } as isize;
+            if centiseconds >= 0 {
+                write!(f, "[{}.{:02}s] ", centiseconds / 100, centiseconds % 100)?;
+            }
+        }
+    }
+
+    #[cfg(feature = "timestamp-sys-time")]
+    {
+        if ::debugger_attached() && ::sink::semihosting_enabled() {
+            // NOTE(unsafe) SYS_TIME takes no parameters
+            let seconds = unsafe { sh::syscall1(nr::TIME, 0) };
+            write!(f, "[unix time {}] ", seconds)?;
+        }
+    }
+
+    #[cfg(feature = "timestamp-dwt")]
+    {
+        if DWT::cycle_counter_enabled() {
+            write!(f, "[{} cycles] ", DWT::cycle_count())?;
+        }
+    }
+
+    #[cfg(feature = "timestamp-user")]
+    {
+        if let Some(clock) = interrupt::free(|cs| CLOCK.borrow(cs).get()) {
+            let now = clock.now();
+            write!(f, "[{}.{:06}s] ", now.as_secs(), now.subsec_micros())?;
+        }
+    }
+
+    Ok(())
+}
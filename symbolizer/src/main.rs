@@ -0,0 +1,350 @@
+//! Symbolizes the addresses in `panic-semihosting` reports
+//!
+//! ``` text
+//! panic-semihosting-symbolizer <ELF> [LOG]
+//! ```
+//!
+//! Reads the semihosting output of a program, from `LOG` or from stdin if omitted, and prints it
+//! back with every code address followed by the function, file and line it belongs to, according
+//! to the debug information in `ELF`. Backtrace frames also list the functions that were inlined
+//! at the call site. Backtrace frames and `LR` values are return addresses, so the call instruction
+//! that precedes them is looked up instead.
+//!
+//! ``` text
+//! $ openocd -f board.cfg 2>&1 | panic-semihosting-symbolizer target/thumbv7m-none-eabi/debug/app
+//! panicked at 'oops', src/main.rs:20:5
+//! stack backtrace (exidx):
+//!   #0 0x08000b4b app::helper at src/main.rs:20:5
+//!                 inlined into app::run at src/main.rs:14:5
+//!   #1 0x080004c5 app::__cortex_m_rt_main at src/main.rs:9:5
+//! ```
+//!
+//! A warning is printed if a report carries a GNU build-id, see the `build-info` feature of
+//! `panic-semihosting`, that doesn't match the one of `ELF`.
+//!
+//! Compressed debug sections are not supported.
+
+#![deny(warnings)]
+
+extern crate addr2line;
+extern crate gimli;
+extern crate object;
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::{env, process};
+
+use addr2line::Context;
+use gimli::{EndianSlice, RunTimeEndian};
+use object::read::{SymbolMap, SymbolMapName};
+use object::{CompressionFormat, Object, ObjectSection, SectionKind};
+
+type Reader<'data> = EndianSlice<'data, RunTimeEndian>;
+
+/// Width of a formatted address, `0x` prefix included
+const ADDRESS_WIDTH: usize = 10;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args_os().skip(1);
+    let (elf, log) = match (args.next(), args.next(), args.next()) {
+        (Some(elf), log, None) => (elf, log),
+        _ => return Err("usage: panic-semihosting-symbolizer <ELF> [LOG]".into()),
+    };
+
+    let data =
+        fs::read(&elf).map_err(|e| format!("could not read {}: {}", elf.to_string_lossy(), e))?;
+    let file = object::File::parse(&*data)
+        .map_err(|e| format!("could not parse {}: {}", elf.to_string_lossy(), e))?;
+    let symbolizer = Symbolizer::new(&file)?;
+
+    let input: Box<dyn BufRead> = match log {
+        Some(log) => {
+            Box::new(BufReader::new(File::open(&log).map_err(|e| {
+                format!("could not open {}: {}", log.to_string_lossy(), e)
+            })?))
+        }
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    for line in input.split(b'\n') {
+        // the program may print anything; don't choke on invalid UTF-8
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        symbolizer.rewrite(line.trim_end_matches('\r'), &mut output)?;
+        // keep up with a live session
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Maps code addresses to source locations
+struct Symbolizer<'data> {
+    context: Context<Reader<'data>>,
+    /// Fallback for code without debug information
+    symbols: SymbolMap<SymbolMapName<'data>>,
+    /// Address ranges of the code sections
+    text: Vec<(u64, u64)>,
+    /// GNU build-id, in hexadecimal
+    build_id: Option<String>,
+}
+
+impl<'data> Symbolizer<'data> {
+    fn new(file: &object::File<'data>) -> Result<Self, Box<dyn Error>> {
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+
+        let load = |id: gimli::SectionId| -> Result<Reader<'data>, Box<dyn Error>> {
+            let data = match file.section_by_name(id.name()) {
+                Some(section) => {
+                    if section.compressed_file_range()?.format != CompressionFormat::None {
+                        return Err(format!("{} is compressed", id.name()).into());
+                    }
+                    section.data()?
+                }
+                None => &[],
+            };
+
+            Ok(EndianSlice::new(data, endian))
+        };
+
+        let dwarf = gimli::Dwarf::load(load)?;
+        let context = Context::from_dwarf(dwarf)
+            .map_err(|e| format!("could not parse the debug information: {}", e))?;
+
+        let text = file
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .map(|section| (section.address(), section.address() + section.size()))
+            .collect();
+
+        Ok(Symbolizer {
+            context,
+            symbols: file.symbol_map(),
+            build_id: file
+                .build_id()?
+                .map(|id| id.iter().map(|byte| format!("{:02x}", byte)).collect()),
+            text,
+        })
+    }
+
+    /// Writes `line` followed by the location of the addresses it contains
+    fn rewrite(&self, line: &str, output: &mut dyn Write) -> io::Result<()> {
+        if let Some(build_id) = line.trim_start().strip_prefix("build-id=") {
+            if self.build_id.as_ref().map(|id| &id[..]) != Some(build_id) {
+                eprintln!(
+                    "warning: the report comes from a different build (build-id {}) than the ELF ({})",
+                    build_id,
+                    self.build_id.as_ref().map(|id| &id[..]).unwrap_or("none")
+                );
+            }
+        }
+
+        if let Some((prefix, address)) = frame(line).filter(|&(_, address)| self.in_text(address)) {
+            let mut functions = self.functions(address, true).into_iter();
+            return match functions.next() {
+                Some(function) => {
+                    writeln!(output, "{} {}", prefix, function)?;
+
+                    // line the callers up with the innermost function
+                    for caller in functions {
+                        writeln!(output, "{:2$}inlined into {}", "", caller, prefix.len() + 1)?;
+                    }
+
+                    Ok(())
+                }
+                None => writeln!(output, "{}", line),
+            };
+        }
+
+        // any other address, e.g. a register value, that points into the code
+        let return_address = holds_return_address(line);
+        let mut rest = line;
+        while let Some((start, address)) = next_address(rest) {
+            let end = start + ADDRESS_WIDTH;
+            output.write_all(&rest.as_bytes()[..end])?;
+
+            if self.in_text(address) {
+                if let Some(function) = self.functions(address, return_address).into_iter().next() {
+                    write!(output, " ({})", function)?;
+                }
+            }
+
+            rest = &rest[end..];
+        }
+        writeln!(output, "{}", rest)
+    }
+
+    /// Returns the functions, innermost first, that contain `address`
+    ///
+    /// `return_address` must be set if `address` is a return address, in which case the call
+    /// instruction that precedes it is looked up.
+    fn functions(&self, address: u64, return_address: bool) -> Vec<String> {
+        // clear the Thumb bit
+        let mut probe = address & !1;
+        if return_address {
+            probe = probe.saturating_sub(1);
+        }
+
+        let mut functions = vec![];
+        if let Ok(mut frames) = self.context.find_frames(probe).skip_all_loads() {
+            while let Ok(Some(frame)) = frames.next() {
+                let name = frame
+                    .function
+                    .as_ref()
+                    .and_then(|function| function.demangle().ok())
+                    .map(Cow::into_owned)
+                    .unwrap_or_else(|| "??".to_owned());
+
+                functions.push(match frame.location {
+                    Some(addr2line::Location {
+                        file: Some(file),
+                        line: Some(line),
+                        column,
+                    }) => match column {
+                        Some(column) => format!("{} at {}:{}:{}", name, file, line, column),
+                        None => format!("{} at {}:{}", name, file, line),
+                    },
+                    _ => name,
+                });
+            }
+        }
+
+        if functions.is_empty() {
+            if let Some(symbol) = self.symbols.get(probe) {
+                functions.push(addr2line::demangle_auto(symbol.name().into(), None).into_owned());
+            }
+        }
+
+        functions
+    }
+
+    fn in_text(&self, address: u64) -> bool {
+        let address = address & !1;
+        self.text
+            .iter()
+            .any(|&(start, end)| start <= address && address < end)
+    }
+}
+
+/// Parses a backtrace frame, `  #N 0xADDRESS`; returns the line up to the end of the address
+fn frame(line: &str) -> Option<(&str, u64)> {
+    let rest = line.trim_start().strip_prefix('#')?;
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 || !rest[digits..].starts_with(' ') {
+        return None;
+    }
+
+    let start = line.len() - rest.len() + digits + 1;
+    match next_address(&line[start..]) {
+        Some((0, address)) if line.len() == start + ADDRESS_WIDTH => Some((line, address)),
+        _ => None,
+    }
+}
+
+/// Returns `true` if `line` is the value of a link register, `  LR = 0xADDRESS`, which holds a
+/// return address
+fn holds_return_address(line: &str) -> bool {
+    match line.trim_start().strip_prefix("LR") {
+        Some(rest) => rest.trim_start().starts_with('='),
+        None => false,
+    }
+}
+
+/// Finds the next `0x` prefixed, 8 digit hexadecimal number; returns its offset and value
+fn next_address(s: &str) -> Option<(usize, u64)> {
+    let bytes = s.as_bytes();
+    let mut offset = 0;
+    while let Some(position) = s[offset..].find("0x") {
+        let start = offset + position;
+        let digits = &bytes[start + 2..];
+        let len = digits.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+        let preceded = start > 0 && bytes[start - 1].is_ascii_alphanumeric();
+
+        if len == ADDRESS_WIDTH - 2 && !preceded {
+            let address = u64::from_str_radix(&s[start + 2..start + ADDRESS_WIDTH], 16).ok()?;
+            return Some((start, address));
+        }
+
+        offset = start + 2;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame, holds_return_address, next_address};
+
+    #[test]
+    fn frames() {
+        assert_eq!(
+            frame("  #0 0x08000b4b"),
+            Some(("  #0 0x08000b4b", 0x0800_0b4b))
+        );
+        assert_eq!(
+            frame("  #12 0x080004c5"),
+            Some(("  #12 0x080004c5", 0x0800_04c5))
+        );
+
+        // not a frame
+        assert_eq!(frame("  # 0x08000b4b"), None);
+        assert_eq!(frame("  #0x08000b4b"), None);
+        assert_eq!(frame("  #0 0x08000b4b (inlined)"), None);
+        assert_eq!(frame("  ... (more frames omitted)"), None);
+
+        // truncated
+        assert_eq!(frame("  #1 0x0800"), None);
+        assert_eq!(frame("  #1 "), None);
+        assert_eq!(frame("  #"), None);
+    }
+
+    #[test]
+    fn return_addresses() {
+        assert!(holds_return_address("  LR        = 0x08000b4b"));
+        assert!(holds_return_address("  LR         = 0x08000b4b"));
+
+        assert!(!holds_return_address("  PC         = 0x08000b52"));
+        assert!(!holds_return_address("  EXC_RETURN = 0xfffffff9"));
+        assert!(!holds_return_address("  #0 0x08000b4b"));
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(
+            next_address("  PC         = 0x08000b52"),
+            Some((15, 0x0800_0b52))
+        );
+        assert_eq!(
+            next_address("  R0 = 0x00000000, R1 = 0x20000400"),
+            Some((7, 0))
+        );
+        assert_eq!(
+            next_address("a0x08000b52, 0x08000b52"),
+            Some((13, 0x0800_0b52))
+        );
+
+        // not 8 digits
+        assert_eq!(next_address("0x0800b52"), None);
+        assert_eq!(next_address("0x008000b52"), None);
+        // part of another token
+        assert_eq!(next_address("a0x08000b52"), None);
+        // truncated
+        assert_eq!(next_address("  PC         = 0x0800"), None);
+        assert_eq!(next_address("  PC         = 0x"), None);
+    }
+}